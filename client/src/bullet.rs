@@ -1,7 +1,5 @@
 use ggez::graphics;
 
-use common::{BULLET_UPDATE_INTERVAL, BULLET_UPDATE_MOVEMENT};
-
 #[derive(Debug)]
 pub struct Bullet {
     x: f32, // current x
@@ -9,13 +7,14 @@ pub struct Bullet {
 
     start_y: f32,
     final_y: f32,
+    velocity: f32, // in pixels per ms
     dt: f32, // delta time
 
     body: graphics::Mesh,
 }
 
 impl Bullet {
-    pub fn new(ctx: &mut ggez::Context, x: f32, y: f32, start_y: f32, final_y: f32) -> Bullet {
+    pub fn new(ctx: &mut ggez::Context, x: f32, y: f32, start_y: f32, final_y: f32, velocity: f32) -> Bullet {
         let body = graphics::Mesh::new_circle(
             ctx,
             graphics::DrawMode::fill(),
@@ -30,6 +29,7 @@ impl Bullet {
             y,
             start_y,
             final_y,
+            velocity,
             dt: 0.0,
             body,
         }
@@ -49,7 +49,7 @@ impl Bullet {
     }
 
     pub fn update(&mut self) -> bool {
-        let v = (self.start_y-self.final_y)/((((self.start_y-self.final_y).abs()*BULLET_UPDATE_INTERVAL as f32)/BULLET_UPDATE_MOVEMENT as f32)/self.dt);
+        let v = (self.start_y-self.final_y)/(((self.start_y-self.final_y).abs()/self.velocity)/self.dt);
         if (v >= 0.0 && self.y >= v) || (v <= 0.0 && self.y <= (self.final_y + v)) {
             self.y -= v;
         } else {
@@ -59,10 +59,23 @@ impl Bullet {
         self.y != self.final_y
     }
 
-    // pub fn move_to(&mut self, y: f32) -> &mut Self {
-    //     self.y = y;
-    //     self
-    // }
+    // moves on by the distance it flies in the given time, e.g. since the server spawned it
+    pub fn fast_forward(&mut self, ms: f32) -> &mut Self {
+        let distance = self.velocity * ms;
+        self.y = if self.final_y < self.y {
+            (self.y - distance).max(self.final_y)
+        } else {
+            (self.y + distance).min(self.final_y)
+        };
+        self
+    }
+
+    // where the server says it is, it flies on from there
+    pub fn move_to(&mut self, x: f32, y: f32) -> &mut Self {
+        self.x = x;
+        self.y = y;
+        self
+    }
 
     pub fn draw(&self, ctx: &mut ggez::Context) -> ggez::GameResult {
         graphics::draw(ctx, &self.body, (ggez::mint::Point2::from([self.x, self.y]),))
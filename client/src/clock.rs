@@ -0,0 +1,53 @@
+use std::{collections::VecDeque, time::Instant};
+
+const MAX_SAMPLES: usize = 8; // recent exchanges the estimate is picked from
+
+// our idea of the server's clock, NTP style: we ask for its time and take the reply to have
+// spent half the round trip on the way back. The exchange with the shortest round trip
+// is the one the network skewed the least
+pub struct ServerClock {
+    start: Instant,
+    samples: VecDeque<(i64, i64)>, // round trip and server time minus ours, in ms
+}
+
+impl ServerClock {
+    pub fn new() -> ServerClock {
+        ServerClock {
+            start: Instant::now(),
+            samples: VecDeque::new(),
+        }
+    }
+
+    // for a TimeRequest
+    pub fn local_time(&self) -> u32 {
+        self.local() as u32
+    }
+
+    pub fn sync(&mut self, client_time: u32, server_time: u32) {
+        let now = self.local();
+        let round_trip = now - client_time as i64;
+        if round_trip < 0 {
+            return; // not a time we sent
+        }
+        self.samples.push_back((round_trip, server_time as i64 + round_trip / 2 - now));
+        if self.samples.len() > MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+    }
+
+    // None until the server answered
+    pub fn now(&self) -> Option<u32> {
+        self.samples.iter()
+            .min_by_key(|(round_trip, _)| *round_trip)
+            .map(|(_, offset)| (self.local() + offset) as u32)
+    }
+
+    // ms since the server stamped a time, 0 until we know its clock
+    pub fn since(&self, time: u32) -> f32 {
+        self.now().map_or(0.0, |now| (now.wrapping_sub(time) as i32).max(0) as f32)
+    }
+
+    fn local(&self) -> i64 {
+        self.start.elapsed().as_millis() as i64
+    }
+}
@@ -0,0 +1,115 @@
+use std::path::PathBuf;
+
+use serde::Deserialize;
+use structopt::StructOpt;
+
+use common::*;
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "goshooter_client", about = "GoShooter multiplayer client")]
+struct Opt {
+    /// TOML config file, command-line flags override its values
+    #[structopt(short, long, parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Server to join, as host:port
+    #[structopt(short, long)]
+    server: Option<String>,
+    /// Display name, at most 32 characters
+    #[structopt(short, long)]
+    name: Option<String>,
+    /// Room to join, any open room if not given
+    #[structopt(short, long)]
+    room: Option<String>,
+    /// Watch both teams of a room instead of playing
+    #[structopt(long)]
+    spectate: bool,
+    /// Start in (desktop) fullscreen
+    #[structopt(short, long)]
+    fullscreen: bool,
+    /// Window scale factor
+    #[structopt(long)]
+    scale: Option<f32>,
+    /// Packet encoding, binary or json; must match the server
+    #[structopt(long)]
+    wire_format: Option<WireFormat>,
+    /// What to connect over, tcp or udp; must match the server
+    #[structopt(long)]
+    transport: Option<Transport>,
+    /// How far behind the server other players are shown, in ms, to smooth out the network
+    #[structopt(long)]
+    interp_delay: Option<u32>,
+    /// How long other players keep moving when their positions are late, in ms
+    #[structopt(long)]
+    max_extrapolation: Option<u32>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: String,
+    pub name: String,
+    pub room: String, // empty for any open room
+    pub spectate: bool,
+    pub fullscreen: bool,
+    pub scale: f32,
+    pub wire_format: WireFormat,
+    pub transport: Transport,
+    pub interp_delay: u32, // in ms
+    pub max_extrapolation: u32, // in ms
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            server: String::from("127.0.0.1:6773"),
+            name: String::from("test"),
+            room: String::new(),
+            spectate: false,
+            fullscreen: false,
+            scale: 1.0,
+            wire_format: WireFormat::Binary,
+            transport: Transport::Tcp,
+            interp_delay: 150,
+            max_extrapolation: 100,
+        }
+    }
+}
+
+impl Config {
+    // defaults, overridden by the config file, overridden by command-line flags
+    pub fn load() -> std::result::Result<Config, String> {
+        let opt = Opt::from_args();
+        let mut config = match &opt.config {
+            Some(path) => {
+                let raw = std::fs::read_to_string(path)
+                    .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+                toml::from_str(&raw).map_err(|e| format!("invalid config {}: {}", path.display(), e))?
+            }
+            None => Config::default(),
+        };
+
+        if let Some(server) = opt.server { config.server = server; }
+        if let Some(name) = opt.name { config.name = name; }
+        if let Some(room) = opt.room { config.room = room; }
+        if opt.spectate { config.spectate = true; }
+        if opt.fullscreen { config.fullscreen = true; }
+        if let Some(scale) = opt.scale { config.scale = scale; }
+        if let Some(wire_format) = opt.wire_format { config.wire_format = wire_format; }
+        if let Some(transport) = opt.transport { config.transport = transport; }
+        if let Some(interp_delay) = opt.interp_delay { config.interp_delay = interp_delay; }
+        if let Some(max_extrapolation) = opt.max_extrapolation { config.max_extrapolation = max_extrapolation; }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> std::result::Result<(), String> {
+        if self.name.trim().is_empty() {
+            return Err(String::from("name must not be empty"));
+        }
+        if !(self.scale > 0.0 && self.scale <= 8.0) {
+            return Err(format!("scale must be between 0 and 8, got {}", self.scale));
+        }
+        Ok(())
+    }
+}
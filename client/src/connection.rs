@@ -0,0 +1,119 @@
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use common::*;
+
+use crate::config::Config;
+
+const SERVER_TIMEOUT: Duration = Duration::from_secs(5); // silence over UDP before we call the server gone, it pings every second
+
+// our end of the connection to the server, see Config::transport
+pub enum Connection {
+    Tcp(BlockingFramed<TcpStream, PacketCodec>),
+    Udp(UdpConnection),
+}
+
+impl Connection {
+    // blocking until set_nonblocking is called
+    pub fn open(config: &Config) -> Result<Connection> {
+        let codec = PacketCodec::new(config.wire_format);
+        match config.transport {
+            Transport::Tcp => Ok(Connection::Tcp(BlockingFramed::new(TcpStream::connect(&config.server)?, codec))),
+            Transport::Udp => Ok(Connection::Udp(UdpConnection::open(&config.server, codec)?)),
+        }
+    }
+
+    pub fn set_nonblocking(&self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(conn) => conn.get_ref().set_nonblocking(true),
+            Connection::Udp(conn) => conn.socket.set_nonblocking(true),
+        }
+    }
+
+    pub fn send(&mut self, packet: Packet) -> Result<()> {
+        match self {
+            Connection::Tcp(conn) => conn.send(packet),
+            Connection::Udp(conn) => conn.send(packet),
+        }
+    }
+
+    // the next packet, None if there is none yet
+    pub fn recv(&mut self) -> Result<Option<Result<Packet>>> {
+        match self {
+            Connection::Tcp(conn) => conn.recv(),
+            Connection::Udp(conn) => conn.recv(),
+        }
+    }
+}
+
+// a socket of our own, talking to the server through a Link
+pub struct UdpConnection {
+    socket: UdpSocket,
+    link: Link,
+    last_heard: Instant,
+    buf: Vec<u8>,
+}
+
+impl UdpConnection {
+    fn open(server: &str, codec: PacketCodec) -> Result<UdpConnection> {
+        let addr = server.to_socket_addrs()?.next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("could not resolve {}", server)))?;
+        let local = match addr {
+            SocketAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+            SocketAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(addr)?;
+        socket.set_read_timeout(Some(RESEND_AFTER))?; // wakes a blocking recv up to send again what was lost
+        Ok(UdpConnection {
+            socket,
+            link: Link::new(codec),
+            last_heard: Instant::now(),
+            buf: vec![0; MAX_DATAGRAM_LEN],
+        })
+    }
+
+    fn send(&mut self, packet: Packet) -> Result<()> {
+        let datagram = self.link.send(packet, Instant::now())?;
+        self.send_datagram(&datagram)
+    }
+
+    fn recv(&mut self) -> Result<Option<Result<Packet>>> {
+        for datagram in self.link.resend(Instant::now()) {
+            self.send_datagram(&datagram)?;
+        }
+        loop {
+            if let Some(packet) = self.link.recv() {
+                return Ok(Some(packet));
+            }
+            match self.socket.recv(&mut self.buf) {
+                Ok(len) => {
+                    self.last_heard = Instant::now();
+                    match self.link.receive(&self.buf[..len]) {
+                        Ok(Some(reply)) => self.send_datagram(&reply)?,
+                        Ok(None) => (),
+                        Err(e) => println!("Invalid datagram from the server: {}", e),
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+                    if self.last_heard.elapsed() > SERVER_TIMEOUT {
+                        return Err(io::Error::from(io::ErrorKind::TimedOut).into());
+                    }
+                    return Ok(None);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn send_datagram(&self, datagram: &[u8]) -> Result<()> {
+        match self.socket.send(datagram) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()), // lost, like on the way
+            result => result.map(|_| ()).map_err(ProtocolError::from),
+        }
+    }
+}
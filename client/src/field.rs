@@ -0,0 +1,294 @@
+use std::collections::{HashMap, VecDeque};
+
+use ggez::*;
+
+use common::*;
+
+use crate::{bullet::Bullet, clock::ServerClock, player::Player};
+
+const MAX_TICKS: usize = 32; // states kept for the server to send Deltas against, as many as it keeps
+
+// bullets and enemies by id, at some tick
+type Objects = (HashMap<String, (u32, u32)>, HashMap<String, (u32, u32)>);
+
+// one team's half of the game: everyone on it but ourselves, its bullets and the enemies falling on it
+pub struct Field {
+    players: HashMap<PlayerId, Player>,
+    bullets: HashMap<String, Bullet>, // enemies too
+    ticks: Ticks,
+    score: u32,
+    health_left: u32,
+}
+
+impl Field {
+    pub fn new(win_score: u32) -> Field {
+        Field {
+            players: HashMap::new(),
+            bullets: HashMap::new(),
+            ticks: Ticks::default(),
+            score: 0,
+            health_left: win_score,
+        }
+    }
+
+    // the players of a new game, ours left out
+    pub fn start(&mut self, ctx: &mut Context, players: Vec<(PlayerId, String)>, rules: &GameRules) {
+        self.players.clear(); // not empty when we come back after a dropped connection
+        self.bullets.clear();
+        self.ticks.clear(); // the server counts from 0 again
+        for (id, name) in players {
+            self.players.insert(id, Player::new(ctx, &name, rules.update_interval as f32));
+        }
+        self.score = 0;
+        self.health_left = rules.win_score;
+    }
+
+    pub fn clear(&mut self) {
+        self.players.clear();
+        self.bullets.clear();
+        self.ticks.clear();
+        self.score = 0;
+    }
+
+    // time is where the server's clock was a little while ago, see Config::interp_delay
+    pub fn update(&mut self, dt: f32, time: Option<u32>, max_extrapolation: u32) {
+        // update others
+        if let Some(time) = time {
+            for (_, player) in self.players.iter_mut() {
+                player.interpolate(time, max_extrapolation);
+            }
+        }
+
+        // update bullets & enemies
+        for (_, bullet) in self.bullets.iter_mut() {
+            bullet.update();
+            bullet.set_dt(dt);
+        }
+    }
+
+    // applies a packet about this field; returns false if it refers to something we never heard of
+    pub fn handle(&mut self, ctx: &mut Context, packet: Packet, dt: f32, rules: &GameRules, height: f32, clock: &ServerClock) -> bool {
+        match packet {
+            Packet::PlayerDestroy(pid) => {
+                self.players.remove(&pid);
+            },
+            Packet::PlayerPos { pid, x, y, time } => {
+                match self.players.get_mut(&pid) {
+                    Some(player) => {
+                        let x = player.get_actual_x(x as f32);
+                        player.push(time, x, y as f32);
+                    }
+                    None => return false,
+                }
+            },
+            // spawned a network latency ago, they have flown on since
+            Packet::BulletCreate { id, x, y, time } => {
+                let velocity = rules.bullet_velocity();
+                self.bullets.entry(id)
+                            .or_insert_with(|| {
+                                let mut bullet = Bullet::new(ctx, x as f32, y as f32, y as f32, 0.0, velocity);
+                                bullet.fast_forward(clock.since(time));
+                                bullet
+                            })
+                            .set_dt(dt);
+            },
+            Packet::BulletDestroy(id) => {
+                println!("bullet {} destroy", id);
+                return self.bullets.remove(&id).is_some() || self.ticks.in_use();
+            },
+            Packet::EnemyCreate { id, x, y, time } => {
+                let velocity = rules.bullet_velocity();
+                self.bullets.entry(id)
+                            .or_insert_with(|| {
+                                let mut enemy = Bullet::new(ctx, x as f32, y as f32, y as f32, height, velocity);
+                                enemy.fast_forward(clock.since(time));
+                                enemy
+                            })
+                            .set_dt(dt);
+            },
+            Packet::EnemyDestroy(id) => {
+                println!("enemy {} destroy", id);
+                return self.bullets.remove(&id).is_some() || self.ticks.in_use();
+            },
+            Packet::BulletHit => {
+                self.score += 1;
+            },
+            Packet::EnemyHit => {
+                self.health_left = self.health_left.saturating_sub(1);
+            }
+            Packet::Snapshot { players, bullets, enemies, score, health } => {
+                self.apply_snapshot(ctx, players, bullets, enemies, rules, height);
+                self.score = score;
+                self.health_left = health;
+            },
+            _ => (),
+        }
+        true
+    }
+
+    // brings bullets and enemies to where the server had them at a tick, instead of where we guessed.
+    // Returns the tick to ack, None if it is old or builds on a tick we do not have
+    pub fn apply_delta(&mut self, ctx: &mut Context, delta: Packet, rules: &GameRules, height: f32, clock: &ServerClock) -> Option<u32> {
+        let (tick, time) = match delta {
+            Packet::Delta { tick, time, .. } => (tick, time),
+            _ => return None,
+        };
+        let (all_bullets, all_enemies) = self.ticks.apply(delta, rules)?;
+
+        self.bullets.retain(|id, _| all_bullets.contains_key(id) || all_enemies.contains_key(id));
+        let (velocity, since) = (rules.bullet_velocity(), clock.since(time));
+        for (objects, final_y) in [(all_bullets, 0.0), (all_enemies, height)] {
+            for (id, (x, y)) in objects {
+                let (x, y) = (*x as f32, *y as f32);
+                self.bullets.entry(id.clone())
+                            .and_modify(|bullet| { bullet.move_to(x, y); })
+                            .or_insert_with(|| Bullet::new(ctx, x, y, y, final_y, velocity))
+                            .fast_forward(since);
+            }
+        }
+        Some(tick)
+    }
+
+    // replaces our view of the field with the server's
+    fn apply_snapshot(&mut self, ctx: &mut Context, players: Vec<PlayerSnapshot>, bullets: Vec<BulletSnapshot>, enemies: Vec<BulletSnapshot>, rules: &GameRules, height: f32) {
+        self.players.retain(|pid, _| players.iter().any(|(id, _, _)| id == pid)); // left while we were not looking
+        for (pid, x, y) in players {
+            if let Some(player) = self.players.get_mut(&pid) {
+                let x = player.get_actual_x(x as f32);
+                player.place(x, y as f32);
+            }
+        }
+        let velocity = rules.bullet_velocity();
+        self.bullets.clear();
+        for (id, x, y) in bullets {
+            self.bullets.insert(id, Bullet::new(ctx, x as f32, y as f32, y as f32, 0.0, velocity));
+        }
+        for (id, x, y) in enemies {
+            self.bullets.insert(id, Bullet::new(ctx, x as f32, y as f32, y as f32, height, velocity));
+        }
+    }
+
+    pub fn hud(&self, win_score: u32) -> String {
+        format!("Health: {}/{}\nScore: {}", self.health_left, win_score, self.score)
+    }
+
+    pub fn draw(&self, ctx: &mut Context) -> GameResult {
+        for (_, player) in self.players.iter() {
+            player.draw(ctx)?;
+        }
+        for (_, bullet) in self.bullets.iter() {
+            bullet.draw(ctx)?;
+        }
+        Ok(())
+    }
+}
+
+// the server's bullets and enemies at the ticks we applied with their time, what its Deltas build on
+#[derive(Default)]
+struct Ticks(VecDeque<(u32, u32, Objects)>);
+
+impl Ticks {
+    fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    // once Deltas come in, one may overtake a reliable destroy and take the object away first
+    fn in_use(&self) -> bool {
+        !self.0.is_empty()
+    }
+
+    // the bullets and enemies a Delta leaves, None if it is old or builds on a tick we do not have
+    fn apply(&mut self, delta: Packet, rules: &GameRules) -> Option<&Objects> {
+        let (tick, time, baseline, bullets, enemies, removed) = match delta {
+            Packet::Delta { tick, time, baseline, bullets, enemies, removed } => (tick, time, baseline, bullets, enemies, removed),
+            _ => return None,
+        };
+        if matches!(self.0.back(), Some((last, _, _)) if *last >= tick) {
+            return None; // overtaken by a newer one
+        }
+        // what the Delta leaves out flew on from where it was, the server works it out the same way
+        let (mut all_bullets, mut all_enemies) = if baseline == 0 {
+            Objects::default()
+        } else {
+            let (_, baseline_time, (bullets, enemies)) = self.0.iter().find(|(applied, _, _)| *applied == baseline)?;
+            let travel = rules.bullet_travel(time.wrapping_sub(*baseline_time));
+            (
+                bullets.iter().map(|(id, (x, y))| (id.clone(), (*x, y.saturating_sub(travel)))).collect(),
+                enemies.iter().map(|(id, (x, y))| (id.clone(), (*x, y.saturating_add(travel)))).collect(),
+            )
+        };
+        for id in &removed {
+            all_bullets.remove(id);
+            all_enemies.remove(id);
+        }
+        all_bullets.extend(bullets.into_iter().map(|(id, x, y)| (id, (x, y))));
+        all_enemies.extend(enemies.into_iter().map(|(id, x, y)| (id, (x, y))));
+
+        // the server only sends against ticks we acked, older ones are not needed anymore
+        self.0.retain(|(applied, _, _)| *applied >= baseline);
+        self.0.push_back((tick, time, (all_bullets, all_enemies)));
+        if self.0.len() > MAX_TICKS {
+            self.0.pop_front();
+        }
+        self.0.back().map(|(_, _, objects)| objects)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delta(tick: u32, baseline: u32, bullets: &[(&str, u32)], removed: &[&str]) -> Packet {
+        Packet::Delta {
+            tick,
+            time: tick * 50,
+            baseline,
+            bullets: bullets.iter().map(|(id, y)| (id.to_string(), 5, *y)).collect(),
+            enemies: Vec::new(),
+            removed: removed.iter().map(|id| id.to_string()).collect(),
+        }
+    }
+
+    fn apply(ticks: &mut Ticks, delta: Packet) -> Option<&Objects> {
+        ticks.apply(delta, &GameRules::default()) // 2 pixels every 8 ms
+    }
+
+    fn ids(objects: &Objects) -> Vec<&str> {
+        let mut ids: Vec<&str> = objects.0.keys().map(String::as_str).collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    #[test]
+    fn deltas_build_on_their_baseline() {
+        let mut ticks = Ticks::default();
+        assert_eq!(ids(apply(&mut ticks, delta(1, 0, &[("a", 100), ("b", 200)], &[])).unwrap()), ["a", "b"]);
+        // the server has not heard our ack for 1 yet, both build on it
+        assert_eq!(ids(apply(&mut ticks, delta(2, 1, &[("a", 90)], &["b"])).unwrap()), ["a"]);
+        let objects = apply(&mut ticks, delta(3, 1, &[("a", 80), ("c", 300)], &["b"])).unwrap();
+        assert_eq!(ids(objects), ["a", "c"]);
+        assert_eq!(objects.0["a"], (5, 80));
+        // removals are repeated until a tick without them is acked
+        assert_eq!(ids(apply(&mut ticks, delta(4, 3, &[], &["a"])).unwrap()), ["c"]);
+    }
+
+    #[test]
+    fn left_out_objects_fly_on() {
+        let mut ticks = Ticks::default();
+        apply(&mut ticks, delta(1, 0, &[("a", 500)], &[])).unwrap();
+        // for 50 ms, then 100 ms
+        assert_eq!(apply(&mut ticks, delta(2, 1, &[], &[])).unwrap().0["a"], (5, 488));
+        assert_eq!(apply(&mut ticks, delta(3, 1, &[], &[])).unwrap().0["a"], (5, 475));
+    }
+
+    #[test]
+    fn outdated_and_unknown_baselines_are_turned_down() {
+        let mut ticks = Ticks::default();
+        apply(&mut ticks, delta(2, 0, &[("a", 100)], &[])).unwrap();
+        assert!(apply(&mut ticks, delta(1, 0, &[("b", 100)], &[])).is_none());
+        assert!(apply(&mut ticks, delta(2, 0, &[("b", 100)], &[])).is_none());
+        assert!(apply(&mut ticks, delta(3, 1, &[], &[])).is_none());
+        // a full state does not need one
+        assert_eq!(ids(apply(&mut ticks, delta(4, 0, &[("b", 100)], &[])).unwrap()), ["b"]);
+    }
+}
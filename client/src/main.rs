@@ -1,19 +1,38 @@
+mod clock;
+mod config;
+mod connection;
+mod field;
 mod player;
 mod bullet;
 
-use std::{collections::HashMap, io::Write, net::TcpStream, time::{self, Instant}};
+use std::{collections::VecDeque, time::{self, Instant}};
 
 use ggez::*;
 
 use mint::Point2;
+use clock::ServerClock;
+use config::Config;
+use connection::Connection;
+use field::Field;
 use player::Player;
-use bullet::Bullet;
 use common::*;
 
 const MOVE_SPEED_PX: f32 = 8.0;
+const DEFAULT_ARENA_SIZE: f32 = 600.0; // arena size until the server sends the real one
+const RECONNECT_ATTEMPTS: u32 = 5; // one per second, well within the server's grace period
+const RESYNC_INTERVAL: time::Duration = time::Duration::from_secs(1); // between two snapshot requests
+const SYNC_INTERVAL: time::Duration = time::Duration::from_secs(2); // between two clock sync requests
 
 fn main() {
-    let name = std::env::args().nth(1).unwrap_or("test".to_string());
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    let fullscreen_type = if config.fullscreen {
+        ggez::conf::FullscreenType::Desktop
+    } else {
+        ggez::conf::FullscreenType::Windowed
+    };
     //let c = conf::Conf::new();
     let (mut ctx, event_loop) = ContextBuilder::new("goshooter", "test")
         .window_setup(ggez::conf::WindowSetup { 
@@ -24,15 +43,15 @@ fn main() {
             srgb: true 
         })
         .window_mode(ggez::conf::WindowMode { 
-            width: 600.0, 
-            height: 600.0, 
+            width: DEFAULT_ARENA_SIZE * config.scale, 
+            height: DEFAULT_ARENA_SIZE * config.scale, 
             maximized: false, 
-            fullscreen_type: ggez::conf::FullscreenType::Windowed, 
+            fullscreen_type, 
             borderless: false, 
-            min_width: 600.0, 
-            min_height: 600.0, 
-            max_width: 600.0, 
-            max_height: 600.0, 
+            min_width: 0.0, 
+            min_height: 0.0, 
+            max_width: 0.0, 
+            max_height: 0.0, 
             resizable: false, 
             visible: true, 
             resize_on_scale_factor_change: false,
@@ -40,93 +59,156 @@ fn main() {
         .build()
         .unwrap();
 
-    let state = State::new(&mut ctx, &name).unwrap();
+    let state = match State::new(&mut ctx, &config) {
+        Ok(state) => state,
+        Err(ProtocolError::Rejected(reason)) => {
+            eprintln!("The server refused to let you join: {}", reason);
+            std::process::exit(1);
+        }
+        Err(ProtocolError::VersionMismatch { local, remote }) => {
+            eprintln!("The server runs protocol version {}, this client needs version {}. Please update.", remote, local);
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Could not join the game: {}", e);
+            std::process::exit(1);
+        }
+    };
+    // draw the lobby in arena coordinates too, the window is resized once GameInfo arrives
+    graphics::set_screen_coordinates(&mut ctx, graphics::Rect::new(0.0, 0.0, state.width, state.height)).unwrap();
     event::run(ctx, event_loop, state);
 }
 
 #[derive(PartialEq, Clone, Copy)]
 enum GameStatus {
-    RUNNING,
-    WON,
-    LOST,
+    Lobby,
+    Running,
+    Won, // by the top team when spectating
+    Lost,
 }
 
 struct State {
-    stream: TcpStream,
+    conn: Connection,
+    reconnecting: Option<crossbeam::channel::Receiver<Option<Connection>>>, // the connection once a new one is up, None if we gave up
+    gone: bool, // lost the connection for good, we quit
+    config: Config,
+    token: String, // lets us take our player back after a dropped connection
+    spectate: bool, // watching both teams, we have no player then
 
+    room: String,
     width: f32,
     height: f32,
+    window_scale: Option<f32>, // None in fullscreen
     game_status: GameStatus,
+    paused: bool, // a team has no players left
+    rules: GameRules,
 
-    players: HashMap<String, Player>,
-    bullets: HashMap<String, Bullet>,
+    lobby_top: Vec<LobbyPlayer>,
+    lobby_bottom: Vec<LobbyPlayer>,
+    countdown: Option<u8>,
+
+    field: Field, // of our team, or of the top team when spectating
+    other_field: Field, // of the bottom team when spectating
+    clock: ServerClock,
     pos_ticker: crossbeam::channel::Receiver<Instant>,
     last_fired: time::Instant,
+    last_resync: time::Instant,
+    last_sync: Option<time::Instant>, // None to ask for the server's time right away
+    last_input: time::Instant,
+    input_seq: u32,
+    pending: VecDeque<(u32, Input)>, // sent but not applied by the server yet, with Control::Inputs
+    predicted: i64, // where our inputs take us, in server coordinates
 
-    name: String,
+    pid: PlayerId,
     player: Player,
-    score: u32,
-    health_left: u32,
     
     // player controls
     move_r: f32,
     move_l: f32,
     moved: bool,
+    moving: bool, // moved before the last position we sent, the next one tells the others we stopped
     fire: bool,
 }
 
 impl State {
-    pub fn new(ctx: &mut Context, name: &str) -> Result<State> {
-        let name_len = name.len();
-        let name = if name_len > 8 { // make name 8 chars long
-            name[..8].to_string()
-        } else if name_len < 8 {
-            name.to_string() + &"\0".repeat(8-name_len)
+    pub fn new(ctx: &mut Context, config: &Config) -> Result<State> {
+        let name = truncate_name(&config.name);
+        let (conn, pid, room, token) = Self::connect(config, &config.room, "")?;
+        if config.spectate {
+            println!("Watching room {}.", room);
         } else {
-            name.to_string()
-        };
-
-        let mut stream = TcpStream::connect("127.0.0.1:6773")?;
-        stream.write_all(&Packet::PlayerJoin(name.to_string()).parse())?;
-
-        println!("Connected as {}, waiting to start.", name.trim());
-
-        // get current game information
-        let (width, height, players) = 
-            if let Packet::GameInfo { width, height, pids } = Packet::read_from(&mut stream)?.unwrap() {
-                let players: HashMap<String, Player> = pids.into_iter()
-                    .filter(|key| { *key != name } )
-                    .map(|key| {
-                        (key.clone(), Player::new(ctx, &key))
-                    }).collect();
-                (width as f32, height as f32, players)
-            } else { 
-                return Err(String::from("Invalid packet recieved").into());
-            };
-        stream.set_nonblocking(true).unwrap();
-        println!("{:?}", players);
+            println!("Connected as {} ({}), waiting in the lobby of room {}.", name, pid, room);
+        }
 
-        let player = Player::new(ctx, &name);
+        let rules = GameRules::default();
+        let player = Player::new(ctx, name, rules.update_interval as f32);
 
         Ok(State {
-            stream,
-            width,
-            height,
-            game_status: GameStatus::RUNNING,
-            players,
-            bullets: HashMap::new(),
+            conn,
+            reconnecting: None,
+            gone: false,
+            config: config.clone(),
+            token,
+            spectate: config.spectate,
+            room,
+            width: DEFAULT_ARENA_SIZE,
+            height: DEFAULT_ARENA_SIZE,
+            window_scale: if config.fullscreen { None } else { Some(config.scale) },
+            game_status: GameStatus::Lobby,
+            paused: false,
+            field: Field::new(rules.win_score),
+            other_field: Field::new(rules.win_score),
+            rules,
+            lobby_top: Vec::new(),
+            lobby_bottom: Vec::new(),
+            countdown: None,
+            clock: ServerClock::new(),
             pos_ticker: crossbeam::channel::tick(std::time::Duration::from_millis(100)),
             last_fired: time::Instant::now(),
-            name,
+            last_resync: time::Instant::now(),
+            last_sync: None,
+            last_input: time::Instant::now(),
+            input_seq: 0,
+            pending: VecDeque::new(),
+            predicted: 0,
+            pid,
             player,
-            score: 0,
-            health_left: GAME_END_SCORE,
             move_r: 0.0,
             move_l: 0.0,
             moved: false,
+            moving: false,
             fire: false,
         })
     }
+
+    // joins the server, as a new player if the token is empty.
+    // Returns the connection along with our player id, room and token
+    fn connect(config: &Config, room: &str, token: &str) -> Result<(Connection, PlayerId, String, String)> {
+        let mut conn = Connection::open(config)?;
+        conn.send(Packet::PlayerJoin{
+            version: PROTOCOL_VERSION,
+            name: truncate_name(&config.name).to_string(),
+            room: truncate_name(room).to_string(),
+            token: token.to_string(),
+            spectate: config.spectate,
+        })?;
+
+        // wait for our player id, the game information follows once the lobby is done
+        let (pid, room, token) = loop {
+            match conn.recv()? {
+                Some(Ok(Packet::JoinAccepted { pid, room, token })) => break (pid, room, token),
+                Some(Ok(Packet::Rejected { reason })) => return Err(ProtocolError::Rejected(reason)),
+                Some(Ok(packet)) => {
+                    return Err(ProtocolError::HandshakeFailed(format!("unexpected packet {:?}", packet)));
+                }
+                Some(Err(ProtocolError::UnknownPacket(_))) => (), // from a newer server, skipped
+                Some(Err(e)) => return Err(e),
+                None => (), // no complete packet yet
+            }
+        };
+        conn.set_nonblocking()?;
+        Ok((conn, pid, room, token))
+    }
 }
 
 impl ggez::event::EventHandler<GameError> for State {
@@ -136,97 +218,95 @@ impl ggez::event::EventHandler<GameError> for State {
         //let dt = timer::delta(ctx).as_millis() as f32;
         //let dt = timer::average_delta(ctx).as_millis() as f32;
         while timer::check_update_time(ctx, DESIRED_FPS) {
-            // update self
-            let dx = self.move_r - self.move_l;
-            if dx != 0.0 {
-                self.player.move_dx(self.move_r-self.move_l);
-                self.moved = true;
+            if let Some(reconnecting) = &self.reconnecting {
+                match reconnecting.try_recv() {
+                    Ok(Some(conn)) => {
+                        println!("Reconnected to room {}", self.room);
+                        self.conn = conn;
+                        self.reconnecting = None;
+                    }
+                    Err(crossbeam::channel::TryRecvError::Empty) => continue,
+                    Ok(None) | Err(crossbeam::channel::TryRecvError::Disconnected) => self.gone = true,
+                }
             }
-            if let Ok(_) = self.pos_ticker.try_recv() {
-                if self.moved {
-                    self.stream.write_all(&Packet::PlayerPos{
-                        pid: self.name.clone(), 
-                        x: self.player.mid_x() as u32, 
-                        y: self.player.y() as u32
-                    }.parse()).unwrap();
+            if self.gone {
+                ggez::event::quit(ctx);
+                return Ok(());
+            }
+
+            if self.game_status == GameStatus::Running && !self.paused && !self.spectate && self.rules.control == Control::Inputs {
+                // the server moves us by one input per update_interval, we move ahead of it the same way
+                if self.last_input.elapsed() >= self.rules.update_interval() {
+                    let input = Input {
+                        left: self.move_l > 0.0,
+                        right: self.move_r > 0.0,
+                        boost: self.move_l.max(self.move_r) > MOVE_SPEED_PX, // shift was held
+                        fire: self.fire,
+                    };
+                    self.input_seq += 1;
+                    self.pending.push_back((self.input_seq, input));
+                    self.predicted = self.predict(self.predicted, input);
+                    let x = self.player.get_actual_x(self.predicted as f32);
+                    let y = self.player.y();
+                    self.player.set_pos(x, y);
+                    self.send(Packet::PlayerInput{pid: self.pid, seq: self.input_seq, input});
+                    self.last_input = time::Instant::now();
+                }
+            } else if self.game_status == GameStatus::Running && !self.paused && !self.spectate {
+                // update self
+                let dx = self.move_r - self.move_l;
+                if dx != 0.0 {
+                    self.player.move_dx(self.move_r-self.move_l);
+                    self.moved = true;
+                }
+                if self.pos_ticker.try_recv().is_ok() {
+                    if self.moved || self.moving {
+                        self.send(Packet::PlayerPos{
+                            pid: self.pid, 
+                            x: self.player.mid_x() as u32, 
+                            y: self.player.y() as u32,
+                            time: 0,
+                        });
+                    }
+                    self.moving = self.moved;
                     self.moved = false;
                 }
-            }
-            // spawn bullets
-            if self.fire && self.last_fired.elapsed() > time::Duration::from_millis(PLAYER_FIRE_INTERVAL) {
-                self.stream.write_all(&Packet::PlayerPos{
-                    pid: self.name.clone(), 
-                    x: self.player.mid_x() as u32, 
-                    y: self.player.y() as u32
-                }.parse()).unwrap();
-                self.stream.write_all(&Packet::PlayerEvent{pid: self.name.clone(), event: PlayerEvent::Fire}.parse()).unwrap();
-                self.last_fired = time::Instant::now();
+                // spawn bullets
+                if self.fire && self.last_fired.elapsed() > self.rules.fire_interval() {
+                    self.send(Packet::PlayerPos{
+                        pid: self.pid, 
+                        x: self.player.mid_x() as u32, 
+                        y: self.player.y() as u32,
+                        time: 0,
+                    });
+                    self.send(Packet::PlayerEvent{pid: self.pid, event: PlayerEvent::Fire});
+                    self.last_fired = time::Instant::now();
+                }
             }
 
-            // update others
-            for (_, player) in self.players.iter_mut() {
-                player.update();
-                player.set_dt(dt);
+            if !self.paused {
+                if self.rules.control == Control::Inputs {
+                    self.player.update();
+                    self.player.set_dt(dt);
+                }
+                let time = self.clock.now().map(|now| now.wrapping_sub(self.config.interp_delay));
+                self.field.update(dt, time, self.config.max_extrapolation);
+                self.other_field.update(dt, time, self.config.max_extrapolation);
             }
 
-            // update bullets & enemies
-            for (_, bullet) in self.bullets.iter_mut() {
-                bullet.update();
-                bullet.set_dt(dt);
+            // keep our idea of the server's clock fresh
+            if self.last_sync.is_none_or(|at| at.elapsed() >= SYNC_INTERVAL) {
+                self.send(Packet::TimeRequest{ client_time: self.clock.local_time() });
+                self.last_sync = Some(time::Instant::now());
             }
 
-            // handle packets
-            if let Ok(Some(packet)) = Packet::read_from(&mut self.stream) {
-                println!("recv: {:?}", packet);
-                match packet {
-                    Packet::PlayerDestroy(pid) => {
-                        self.players.remove(&pid);
-                    },
-                    Packet::PlayerPos { pid, x, y  } => {
-                        if let Some(player) = self.players.get_mut(&pid) {
-                            let x = player.get_actual_x(x as f32);
-                            player.set_pos(x, y as f32)
-                                  .set_dt(dt);
-                        }
-                    },
-                    Packet::BulletCreate { id, x, y } => {
-                        self.bullets.entry(id)
-                                    .or_insert_with(|| Bullet::new(ctx, x as f32, y as f32, y as f32, 0.0))
-                                    .set_dt(dt);  
-                    },
-                    Packet::BulletDestroy(id) => {
-                        println!("bullet {} destroy", id);
-                        self.bullets.remove(&id);
-                    },
-                    Packet::EnemyCreate { id, x, y } => {
-                        let final_y = self.height;
-                        self.bullets.entry(id)
-                                    .or_insert_with(|| Bullet::new(ctx, x as f32, y as f32, y as f32, final_y))
-                                    .set_dt(dt);
-                    },
-                    Packet::EnemyDestroy(id) => {
-                        println!("enemy {} destroy", id);
-                        self.bullets.remove(&id);
-                    },
-                    Packet::BulletHit => {
-                        self.score += 1;
-                    },
-                    Packet::EnemyHit => {
-                        self.health_left -= 1;
-                    }
-                    Packet::GameWon => {
-                        //self.won = Some(true);
-                        println!("WON");
-                        self.game_status = GameStatus::WON;
-                        //ggez::event::quit(ctx);
-                    }, 
-                    Packet::GameLost => {
-                        //self.won = Some(true);
-                        println!("LOST");
-                        self.game_status = GameStatus::LOST;
-                        //ggez::event::quit(ctx);
-                    },
-                    _ => (),
+            // handle every packet that came in since the last frame
+            while self.reconnecting.is_none() && !self.gone {
+                match self.conn.recv() {
+                    Ok(Some(Ok(packet))) => self.handle_packet(ctx, packet, dt, true)?,
+                    Ok(Some(Err(_))) => (),
+                    Ok(None) => break,
+                    Err(e) => self.lose_connection(e),
                 }
             }
         }
@@ -236,30 +316,55 @@ impl ggez::event::EventHandler<GameError> for State {
     fn draw(&mut self, ctx: &mut Context) -> GameResult {
         graphics::clear(ctx, [0.1, 0.2, 0.3, 1.0].into());
 
-        if self.game_status != GameStatus::RUNNING {
-            self.game_over(ctx, self.game_status)?;
-            return Ok(());
+        match self.game_status {
+            GameStatus::Lobby => {
+                self.draw_lobby(ctx)?;
+                graphics::present(ctx)?;
+                return Ok(());
+            }
+            GameStatus::Won | GameStatus::Lost => {
+                self.game_over(ctx, self.game_status)?;
+                graphics::present(ctx)?;
+                return Ok(());
+            }
+            GameStatus::Running => (),
         }
         
         // draw scores
-        let scores = format!("Health: {}/{}\nScore: {}", self.health_left, GAME_END_SCORE, self.score);
+        let mut scores = self.field.hud(self.rules.win_score);
+        if self.spectate {
+            scores = format!("Top team\n{}", scores);
+        }
+        if self.paused {
+            scores += "\nPaused, waiting for players to come back";
+        }
+        if self.reconnecting.is_some() {
+            scores += "\nConnection lost, reconnecting...";
+        }
         graphics::draw(ctx, &graphics::Text::new(scores), (Point2::from([0.0,0.0]),)).unwrap();
 
         // draw objects
-        self.player.draw(ctx)?;
-
-        for (_, player) in self.players.iter() {
-            player.draw(ctx)?;
+        if !self.spectate {
+            self.player.draw(ctx)?;
         }
-        for (_, bullet) in self.bullets.iter() {
-            bullet.draw(ctx)?;
+        self.field.draw(ctx)?;
+
+        if self.spectate {
+            // the bottom team's field right of the top team's, in its own arena coordinates
+            let divider = graphics::Mesh::new_line(ctx, &[Point2::from([self.width, 0.0]), Point2::from([self.width, self.height])], 2.0, graphics::Color::WHITE)?;
+            graphics::draw(ctx, &divider, (Point2::from([0.0, 0.0]),))?;
+            graphics::set_screen_coordinates(ctx, graphics::Rect::new(-self.width, 0.0, self.width * 2.0, self.height))?;
+            let scores = format!("Bottom team\n{}", self.other_field.hud(self.rules.win_score));
+            graphics::draw(ctx, &graphics::Text::new(scores), (Point2::from([0.0,0.0]),))?;
+            self.other_field.draw(ctx)?;
+            graphics::set_screen_coordinates(ctx, graphics::Rect::new(0.0, 0.0, self.width * 2.0, self.height))?;
         }
 
         graphics::present(ctx)?;
         Ok(())
     }
 
-    fn key_down_event(&mut self, _ctx: &mut Context, keycode: event::KeyCode, _keymods: event::KeyMods, _repeat: bool) {
+    fn key_down_event(&mut self, ctx: &mut Context, keycode: event::KeyCode, _keymods: event::KeyMods, _repeat: bool) {
         let shift = if _keymods == event::KeyMods::SHIFT { // speed increase on shift
             2.5
         } else {
@@ -270,11 +375,23 @@ impl ggez::event::EventHandler<GameError> for State {
             event::KeyCode::Right => self.move_r = shift*MOVE_SPEED_PX,
             event::KeyCode::Left => self.move_l = shift*MOVE_SPEED_PX,
             event::KeyCode::Up => self.fire = true,
+            event::KeyCode::Space if self.game_status == GameStatus::Lobby && !self.spectate && !_repeat => {
+                self.send(Packet::PlayerEvent{pid: self.pid, event: PlayerEvent::Ready});
+            },
+            event::KeyCode::Escape => {
+                self.exit();
+                ggez::event::quit(ctx);
+            },
             _ => (),
         }
         
     }
 
+    fn quit_event(&mut self, _ctx: &mut Context) -> bool {
+        self.exit();
+        false
+    }
+
     fn key_up_event(&mut self, _ctx: &mut Context, _keycode: event::KeyCode, _keymods: event::KeyMods) {
         match _keycode {
             event::KeyCode::Right => self.move_r = 0.0,
@@ -286,17 +403,212 @@ impl ggez::event::EventHandler<GameError> for State {
 }
 
 impl State {
-    fn game_over(&mut self, ctx: &mut Context, game_status: GameStatus) -> GameResult {
-        let text = graphics::Text::new(if game_status == GameStatus::WON {
-            "Your team won"
+    // top is false for packets about the bottom team's field when spectating
+    fn handle_packet(&mut self, ctx: &mut Context, packet: Packet, dt: f32, top: bool) -> GameResult {
+        match packet {
+            Packet::Ping => {
+                self.send(Packet::Pong);
+            },
+            Packet::TimeReply { client_time, server_time } => {
+                self.clock.sync(client_time, server_time);
+            },
+            Packet::Spectated { top, packet } => {
+                return self.handle_packet(ctx, *packet, dt, top);
+            },
+            Packet::LobbyInfo { top, bottom } => {
+                if self.game_status != GameStatus::Lobby { // back for a rematch
+                    self.game_status = GameStatus::Lobby;
+                    self.paused = false;
+                    self.field.clear();
+                    self.other_field.clear();
+                }
+                self.lobby_top = top;
+                self.lobby_bottom = bottom;
+                self.countdown = None; // restarted by the server if everyone is still ready
+            },
+            Packet::Countdown { seconds } => {
+                self.countdown = Some(seconds);
+            },
+            Packet::GameInfo { version, width, height, rules, players } => {
+                if version != PROTOCOL_VERSION {
+                    eprintln!("The server runs protocol version {}, this client needs version {}. Please update.", version, PROTOCOL_VERSION);
+                    ggez::event::quit(ctx);
+                    return Ok(());
+                }
+                if top {
+                    self.start_game(ctx, width as f32, height as f32, rules, players)?;
+                } else {
+                    self.other_field.start(ctx, players, &rules);
+                }
+            },
+            Packet::GamePaused => {
+                self.paused = true;
+            },
+            Packet::GameResumed => {
+                self.paused = false;
+            },
+            Packet::GameWon | Packet::GameLost => {
+                let won = (packet == Packet::GameWon) == top; // the top team's result when spectating
+                println!("{}", if won { "WON" } else { "LOST" });
+                self.game_status = if won { GameStatus::Won } else { GameStatus::Lost };
+            },
+            Packet::PlayerPos { pid, x, y, .. } if pid == self.pid => { // the server turned our last move down
+                let x = self.player.get_actual_x(x as f32);
+                self.player.place(x, y as f32);
+            },
+            Packet::Delta { .. } => {
+                let (rules, height) = (&self.rules, self.height);
+                let field = if top { &mut self.field } else { &mut self.other_field };
+                if let Some(tick) = field.apply_delta(ctx, packet, rules, height, &self.clock) {
+                    if !self.spectate { // spectators always get the whole field
+                        self.send(Packet::TickAck{ tick });
+                    }
+                }
+            },
+            Packet::InputAck { seq, x, y } => {
+                // replay what the server has not seen yet on top of where it put us,
+                // the player then moves toward the result instead of jumping there
+                self.pending.retain(|(pending, _)| *pending > seq);
+                self.predicted = self.pending.iter().fold(x as i64, |x, (_, input)| self.predict(x, *input));
+                let x = self.player.get_actual_x(self.predicted as f32);
+                self.player.set_pos(x, y as f32).set_dt(dt);
+            },
+            packet => {
+                if let (Packet::Snapshot { players, .. }, true) = (&packet, top) {
+                    if let Some((_, x, y)) = players.iter().find(|(pid, _, _)| *pid == self.pid) {
+                        self.predicted = *x as i64;
+                        let x = self.player.get_actual_x(*x as f32);
+                        self.player.place(x, *y as f32);
+                    }
+                }
+                let (rules, height) = (&self.rules, self.height);
+                let field = if top { &mut self.field } else { &mut self.other_field };
+                if !field.handle(ctx, packet, dt, rules, height, &self.clock) {
+                    self.resync();
+                }
+            },
+        }
+        Ok(())
+    }
+
+    fn start_game(&mut self, ctx: &mut Context, width: f32, height: f32, rules: GameRules, mut players: Vec<(PlayerId, String)>) -> GameResult {
+        // draw in arena coordinates, whatever the window size; spectators see both fields side by side
+        let view_width = if self.spectate { width * 2.0 } else { width };
+        graphics::set_screen_coordinates(ctx, graphics::Rect::new(0.0, 0.0, view_width, height))?;
+        if let Some(scale) = self.window_scale {
+            graphics::set_drawable_size(ctx, view_width * scale, height * scale)?;
+        }
+        if let Some(i) = players.iter().position(|(id, _)| *id == self.pid) {
+            let (_, name) = players.remove(i);
+            self.player = Player::new(ctx, &name, rules.update_interval as f32);
+        }
+        self.field.start(ctx, players, &rules);
+        self.pending.clear();
+        self.width = width;
+        self.height = height;
+        self.rules = rules;
+        self.game_status = GameStatus::Running;
+        Ok(())
+    }
+
+    // where an input takes our player in one update_interval, as the server moves it
+    fn predict(&self, x: i64, input: Input) -> i64 {
+        (x + self.rules.step(input)).clamp(0, self.width as i64)
+    }
+
+    fn draw_lobby(&self, ctx: &mut Context) -> GameResult {
+        let mut text = if self.spectate {
+            format!("Watching room {}\n\n", self.room)
         } else {
-            "Your team lost"
+            format!("Lobby of room {}\n\n", self.room)
+        };
+        for (team, players) in [("Top", &self.lobby_top), ("Bottom", &self.lobby_bottom)] {
+            text += &format!("{}:\n", team);
+            for (pid, name, ready) in players {
+                let you = if *pid == self.pid { " (you)" } else { "" };
+                let ready = if *ready { "ready" } else { "not ready" };
+                text += &format!("  {}{} - {}\n", name, you, ready);
+            }
+            text += "\n";
+        }
+        match self.countdown {
+            _ if self.reconnecting.is_some() => text += "Connection lost, reconnecting...",
+            Some(seconds) => text += &format!("Starting in {}...", seconds),
+            None if self.spectate => text += "Waiting for the players to get ready",
+            None => text += "Press Space to toggle ready",
+        }
+        graphics::draw(ctx, &graphics::Text::new(text), (Point2::from([20.0, 20.0]),))
+    }
+
+    // asks the server for a snapshot when we missed something, at most once per RESYNC_INTERVAL
+    fn resync(&mut self) {
+        if self.spectate || self.game_status != GameStatus::Running || self.last_resync.elapsed() < RESYNC_INTERVAL {
+            return;
+        }
+        println!("Out of sync, asking for a snapshot");
+        self.last_resync = time::Instant::now();
+        self.send(Packet::PlayerEvent{pid: self.pid, event: PlayerEvent::Resync});
+    }
+
+    // packets are dropped while we reconnect, the server sends a snapshot once we are back
+    fn send(&mut self, packet: Packet) {
+        if self.reconnecting.is_some() || self.gone {
+            return;
+        }
+        if let Err(e) = self.conn.send(packet) {
+            self.lose_connection(e);
+        }
+    }
+
+    fn lose_connection(&mut self, e: ProtocolError) {
+        println!("Connection to the server lost: {}", e);
+        self.reconnect();
+    }
+
+    // tries to take our player back in the background, the window keeps drawing meanwhile.
+    // The server sends a snapshot of the game once we are back
+    fn reconnect(&mut self) {
+        if self.game_status != GameStatus::Lobby && self.game_status != GameStatus::Running {
+            self.gone = true; // nothing left to come back to
+            return;
+        }
+        let (config, room, token) = (self.config.clone(), self.room.clone(), self.token.clone());
+        let (done_send, done_recv) = crossbeam::channel::bounded(1);
+        std::thread::spawn(move || {
+            for attempt in 1..=RECONNECT_ATTEMPTS {
+                std::thread::sleep(std::time::Duration::from_secs(1));
+                match Self::connect(&config, &room, &token) {
+                    Ok((conn, _, _, _)) => {
+                        let _ = done_send.send(Some(conn));
+                        return;
+                    }
+                    Err(ProtocolError::Rejected(reason)) => {
+                        println!("Could not reconnect: {}", reason);
+                        break;
+                    }
+                    Err(e) => println!("Reconnect attempt {}/{} failed: {}", attempt, RECONNECT_ATTEMPTS, e),
+                }
+            }
+            let _ = done_send.send(None);
         });
-        let center = [self.width/2.0 - text.width(ctx) as f32/2.0, self.height/2.0];
-        graphics::draw(ctx, &text, (Point2::from(center),))?;
-        graphics::present(ctx)?;
-        ggez::timer::sleep(std::time::Duration::from_secs(5));
-        ggez::event::quit(ctx);
-        Ok(())
+        self.reconnecting = Some(done_recv);
+    }
+
+    // tell the server we are leaving, it may be gone already
+    fn exit(&mut self) {
+        let _ = self.conn.send(Packet::PlayerEvent{pid: self.pid, event: PlayerEvent::Exit});
+    }
+
+    // shown until the server starts a rematch or closes the connection
+    fn game_over(&mut self, ctx: &mut Context, game_status: GameStatus) -> GameResult {
+        let text = graphics::Text::new(match (game_status == GameStatus::Won, self.spectate) {
+            (true, false) => "Your team won",
+            (false, false) => "Your team lost",
+            (true, true) => "The top team won",
+            (false, true) => "The bottom team won",
+        });
+        let view_width = if self.spectate { self.width * 2.0 } else { self.width };
+        let center = [view_width/2.0 - text.width(ctx)/2.0, self.height/2.0];
+        graphics::draw(ctx, &text, (Point2::from(center),))
     }
 }
\ No newline at end of file
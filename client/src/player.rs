@@ -1,4 +1,4 @@
-use common::PLAYER_UPDATE_INTERVAL;
+use std::collections::VecDeque;
 
 use ggez::{
     GameResult, 
@@ -7,6 +7,8 @@ use ggez::{
 
 use mint::Point2;
 
+const SAMPLE_GAP: u32 = 200; // in ms, positions further apart mean the player stood still in between
+
 #[derive(Debug)]
 pub struct Player {
     x: f32,
@@ -14,14 +16,16 @@ pub struct Player {
 
     final_x: f32,
     final_y: f32,
+    update_interval: f32, // in ms, how often the server sends positions
     dt: f32, // delta time
+    samples: VecDeque<(u32, f32, f32)>, // positions of other players stamped by the server, oldest first
 
     body: graphics::Image,
     name: graphics::Text,
 }
 
 impl Player {
-    pub fn new(ctx: &mut ggez::Context, name: &str) -> Player {
+    pub fn new(ctx: &mut ggez::Context, name: &str, update_interval: f32) -> Player {
         //let img = include_bytes!("../test2.png");
         //let body = graphics::Image::from_bytes(ctx, img).unwrap();
         let body = graphics::Image::solid(ctx, 50, graphics::Color::WHITE).unwrap();
@@ -32,7 +36,9 @@ impl Player {
             y: 500.0,
             final_x: 0.0,
             final_y: 0.0,
+            update_interval,
             dt: 0.0,
+            samples: VecDeque::new(),
             body,
             name,
         }
@@ -42,6 +48,49 @@ impl Player {
         self.x += dx;
     }
 
+    // jumps to a position instead of moving there
+    pub fn place(&mut self, x: f32, y: f32) {
+        self.x = x;
+        self.y = y;
+        self.final_x = x;
+        self.final_y = y;
+        self.samples.clear();
+    }
+
+    // a position the server stamped, shown once the clock gets there
+    pub fn push(&mut self, time: u32, x: f32, y: f32) {
+        if matches!(self.samples.back(), Some((last, _, _)) if *last >= time) {
+            return; // a newer one overtook it
+        }
+        self.samples.push_back((time, x, y));
+    }
+
+    // moves to where the samples put the player at a server time: between the two around it,
+    // or at most max_extrapolation ms past the newest one, the way it was going
+    pub fn interpolate(&mut self, time: u32, max_extrapolation: u32) {
+        while self.samples.len() > 2 && self.samples[1].0 <= time {
+            self.samples.pop_front();
+        }
+        let (x, y) = match (self.samples.front(), self.samples.get(1)) {
+            (Some(&(t0, x0, y0)), Some(&(t1, x1, y1))) => {
+                let t0 = t0.max(t1.saturating_sub(SAMPLE_GAP));
+                let elapsed = if time > t1 {
+                    t1 - t0 + (time - t1).min(max_extrapolation)
+                } else {
+                    time.saturating_sub(t0)
+                };
+                let f = elapsed as f32 / (t1 - t0) as f32;
+                (x0 + (x1 - x0) * f, y0 + (y1 - y0) * f)
+            }
+            (Some(&(_, x, y)), None) => (x, y),
+            _ => return,
+        };
+        self.x = x;
+        self.y = y;
+        self.final_x = x;
+        self.final_y = y;
+    }
+
     pub fn set_pos(&mut self, x: f32, y: f32) -> &mut Self {
         self.final_x = x;
         self.final_y = y;
@@ -49,7 +98,7 @@ impl Player {
     }
 
     pub fn update(&mut self) -> bool {
-        let v = self.dx() / (PLAYER_UPDATE_INTERVAL as f32 / self.dt);
+        let v = self.dx() / (self.update_interval / self.dt);
         if (v > 0.0 && self.dx() > 0.0) || (v < 0.0 && self.dx() < 0.0) {
             self.move_dx(v);
         } else  {
@@ -80,7 +129,7 @@ impl Player {
     }
 
     pub fn dx(&self) -> f32 {
-        return self.final_x - self.x
+        self.final_x - self.x
     }
     
     pub fn draw(&self, ctx: &mut ggez::Context) -> GameResult {
@@ -0,0 +1,22 @@
+#![no_main]
+use std::io::Cursor;
+
+use futures::StreamExt;
+use libfuzzer_sys::fuzz_target;
+use tokio_util::codec::FramedRead;
+
+use common::{BlockingFramed, PacketCodec, WireFormat};
+
+// feed arbitrary bytes to the blocking and the async decoder, neither may panic
+fuzz_target!(|data: &[u8]| {
+    let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    for format in [WireFormat::Binary, WireFormat::Json] {
+        let mut framed = BlockingFramed::new(Cursor::new(data.to_vec()), PacketCodec::new(format));
+        while let Ok(Some(_)) = framed.recv() {}
+
+        rt.block_on(async {
+            let mut framed = FramedRead::new(data, PacketCodec::new(format));
+            while let Some(Ok(_)) = framed.next().await {}
+        });
+    }
+});
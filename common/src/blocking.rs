@@ -0,0 +1,86 @@
+use std::io::{self, Read, Write};
+
+use bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+const READ_CHUNK: usize = 4096;
+
+/// Blocking counterpart of `tokio_util::codec::Framed` for `std::io` streams.
+///
+/// Works on non-blocking sockets too: partial reads stay buffered until a whole frame
+/// is there, and writes that would block are kept and retried on the next `send`/`flush`.
+pub struct BlockingFramed<T, C> {
+    inner: T,
+    codec: C,
+    read_buf: BytesMut,
+    write_buf: BytesMut,
+}
+
+impl<T: Read + Write, C> BlockingFramed<T, C> {
+    pub fn new(inner: T, codec: C) -> Self {
+        BlockingFramed {
+            inner,
+            codec,
+            read_buf: BytesMut::new(),
+            write_buf: BytesMut::new(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Returns the next item, or `Ok(None)` if a non-blocking stream has no full frame yet.
+    /// A closed stream is reported as `UnexpectedEof`.
+    pub fn recv(&mut self) -> Result<Option<C::Item>, C::Error>
+    where
+        C: Decoder,
+    {
+        loop {
+            if let Some(item) = self.codec.decode(&mut self.read_buf)? {
+                return Ok(Some(item));
+            }
+
+            let mut chunk = [0; READ_CHUNK];
+            match self.inner.read(&mut chunk) {
+                Ok(0) => {
+                    return match self.codec.decode_eof(&mut self.read_buf)? {
+                        Some(item) => Ok(Some(item)),
+                        None => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                    };
+                }
+                Ok(n) => self.read_buf.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    pub fn send<I>(&mut self, item: I) -> Result<(), C::Error>
+    where
+        C: Encoder<I>,
+    {
+        self.codec.encode(item, &mut self.write_buf)?;
+        self.flush()?;
+        Ok(())
+    }
+
+    /// Writes out as much of the buffered data as the stream takes without blocking.
+    pub fn flush(&mut self) -> io::Result<()> {
+        while !self.write_buf.is_empty() {
+            match self.inner.write(&self.write_buf) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => self.write_buf.advance(n),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        self.inner.flush()
+    }
+}
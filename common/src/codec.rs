@@ -0,0 +1,145 @@
+use std::{fmt, str::FromStr};
+
+use bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{Packet, ProtocolError, Result};
+
+// frame header: packet id (u8) + payload length (u16)
+pub const FRAME_HEADER_LEN: usize = 3;
+#[cfg(feature = "serde")]
+const JSON_LINE_MAX: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(rename_all = "lowercase"))]
+pub enum WireFormat {
+    #[default]
+    Binary,
+    #[cfg(feature = "serde")]
+    Json, // one JSON object per line
+}
+
+impl FromStr for WireFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "binary" => Ok(Self::Binary),
+            #[cfg(feature = "serde")]
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown wire format '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for WireFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Binary => write!(f, "binary"),
+            #[cfg(feature = "serde")]
+            Self::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// Encodes and decodes packets in the chosen `WireFormat`.
+///
+/// Binary frames are laid out as `id (u8), payload length (u16), payload`.
+/// A frame (or JSON line) is always consumed whole, so a malformed or unknown packet is
+/// handed out as an `Err` item instead of failing the stream.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PacketCodec {
+    format: WireFormat,
+}
+
+impl PacketCodec {
+    pub fn new(format: WireFormat) -> Self {
+        PacketCodec { format }
+    }
+
+    pub fn format(&self) -> WireFormat {
+        self.format
+    }
+
+    fn decode_binary(src: &mut BytesMut) -> Option<Result<Packet>> {
+        if src.len() < FRAME_HEADER_LEN {
+            return None;
+        }
+        let id = src[0];
+        let len = u16::from_be_bytes([src[1], src[2]]) as usize;
+        if src.len() < FRAME_HEADER_LEN + len {
+            src.reserve(FRAME_HEADER_LEN + len - src.len());
+            return None;
+        }
+
+        src.advance(FRAME_HEADER_LEN);
+        let payload = src.split_to(len);
+        Some(Packet::decode(id, &payload))
+    }
+
+    fn encode_binary(packet: Packet, dst: &mut BytesMut) -> Result<()> {
+        let start = dst.len();
+        dst.put_u8(packet.id());
+        dst.put_u16(0); // patched below
+        packet.encode(dst);
+
+        let len = dst.len() - start - FRAME_HEADER_LEN;
+        if len > u16::MAX as usize {
+            dst.truncate(start);
+            return Err(ProtocolError::PacketTooLarge);
+        }
+        dst[start+1..start+FRAME_HEADER_LEN].copy_from_slice(&(len as u16).to_be_bytes());
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    fn decode_json(src: &mut BytesMut) -> Result<Option<Result<Packet>>> {
+        loop {
+            let end = match src.iter().position(|b| *b == b'\n') {
+                Some(end) => end,
+                None if src.len() > JSON_LINE_MAX => {
+                    return Err(ProtocolError::PacketTooLarge);
+                }
+                None => return Ok(None),
+            };
+            let line = src.split_to(end + 1);
+            let line = &line[..end];
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            return Ok(Some(serde_json::from_slice(line).map_err(|e| e.into())));
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    fn encode_json(packet: Packet, dst: &mut BytesMut) -> Result<()> {
+        serde_json::to_writer((&mut *dst).writer(), &packet)?;
+        dst.put_u8(b'\n');
+        Ok(())
+    }
+}
+
+impl Decoder for PacketCodec {
+    type Item = Result<Packet>;
+    type Error = ProtocolError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        match self.format {
+            WireFormat::Binary => Ok(Self::decode_binary(src)),
+            #[cfg(feature = "serde")]
+            WireFormat::Json => Self::decode_json(src),
+        }
+    }
+}
+
+impl Encoder<Packet> for PacketCodec {
+    type Error = ProtocolError;
+
+    fn encode(&mut self, packet: Packet, dst: &mut BytesMut) -> Result<()> {
+        match self.format {
+            WireFormat::Binary => Self::encode_binary(packet, dst),
+            #[cfg(feature = "serde")]
+            WireFormat::Json => Self::encode_json(packet, dst),
+        }
+    }
+}
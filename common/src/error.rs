@@ -0,0 +1,67 @@
+use std::{error::Error, fmt, io};
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    UnexpectedEof, // connection closed
+    Io(io::Error),
+    UnknownPacket(u8),
+    InvalidEvent(u8),
+    InvalidUtf8,
+    Truncated, // payload shorter than the packet's fields
+    PacketTooLarge,
+    InvalidDatagram(u8), // unknown kind in the header of a UDP datagram
+    #[cfg(feature = "serde")]
+    Json(serde_json::Error),
+    VersionMismatch { local: u16, remote: u16 },
+    Rejected(String), // join refused by the server
+    HandshakeFailed(String),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnexpectedEof => write!(f, "connection closed"),
+            Self::Io(e) => write!(f, "{}", e),
+            Self::UnknownPacket(id) => write!(f, "unknown packet id {:#04x}", id),
+            Self::InvalidEvent(event) => write!(f, "invalid player event {}", event),
+            Self::InvalidUtf8 => write!(f, "invalid utf-8 string"),
+            Self::Truncated => write!(f, "packet too short"),
+            Self::PacketTooLarge => write!(f, "packet too large"),
+            Self::InvalidDatagram(kind) => write!(f, "unknown datagram kind {}", kind),
+            #[cfg(feature = "serde")]
+            Self::Json(e) => write!(f, "invalid json packet: {}", e),
+            Self::VersionMismatch { local, remote } => {
+                write!(f, "protocol version mismatch: ours {}, theirs {}", local, remote)
+            }
+            Self::Rejected(reason) => write!(f, "rejected by server: {}", reason),
+            Self::HandshakeFailed(reason) => write!(f, "handshake failed: {}", reason),
+        }
+    }
+}
+
+impl Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            #[cfg(feature = "serde")]
+            Self::Json(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ProtocolError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => Self::UnexpectedEof,
+            _ => Self::Io(e),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<serde_json::Error> for ProtocolError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Json(e)
+    }
+}
@@ -1,15 +1,24 @@
-use std::{io::Read, marker::Unpin};
-use byteorder::ReadBytesExt;
-use tokio::io::{AsyncBufRead, AsyncReadExt};
-use bytes::{BufMut, BytesMut};
-
-pub const PLAYER_FIRE_INTERVAL:   u64 = 300; // in ms
-pub const PLAYER_UPDATE_INTERVAL: u64 = 50;  // in ms
-pub const BULLET_UPDATE_INTERVAL: u64 = 8;   // in ms
-pub const BULLET_UPDATE_MOVEMENT: u32 = 2;   // in pixels
-pub const GAME_END_SCORE:         u32 = 100;
+use bytes::{Buf, BufMut, BytesMut};
+
+mod blocking;
+mod codec;
+mod error;
+mod link;
+mod rules;
+
+pub use blocking::BlockingFramed;
+pub use error::ProtocolError;
+pub use codec::{PacketCodec, WireFormat};
+pub use link::{Link, Transport, MAX_DATAGRAM_LEN, RESEND_AFTER};
+pub use rules::{Control, GameRules};
+
 pub const BULLET_ID_LEN: usize = 8;
-pub const PLAYER_ID_MAX: usize = 8;
+pub const PLAYER_NAME_MAX: usize = 32; // in chars
+
+// bumped whenever the wire format changes. The frame header, the version at the start
+// of PlayerJoin and the layout of Rejected must stay the same so mismatched peers can
+// still be turned away.
+pub const PROTOCOL_VERSION: u16 = 18;
 
 // packet ids
 const GAME_INFO:      u8 = 0x01;
@@ -25,298 +34,491 @@ const ENEMY_DESTROY:  u8 = 0x0A;
 const ENEMY_HIT:      u8 = 0x0B;
 const GAME_WON:       u8 = 0x0C;
 const GAME_LOST:      u8 = 0x0D;
+const REJECTED:       u8 = 0x0E;
+const JOIN_ACCEPTED:  u8 = 0x0F;
+const LOBBY_INFO:     u8 = 0x10;
+const COUNTDOWN:      u8 = 0x11;
+const PING:           u8 = 0x12;
+const PONG:           u8 = 0x13;
+const GAME_PAUSED:    u8 = 0x14;
+const GAME_RESUMED:   u8 = 0x15;
+const SNAPSHOT:       u8 = 0x16;
+const SPECTATED:      u8 = 0x17;
+const PLAYER_INPUT:   u8 = 0x18;
+const INPUT_ACK:      u8 = 0x19;
+const DELTA:          u8 = 0x1A;
+const TICK_ACK:       u8 = 0x1B;
+const TIME_REQUEST:   u8 = 0x1C;
+const TIME_REPLY:     u8 = 0x1D;
+
+// assigned by the server on join, never reused while the server runs
+pub type PlayerId = u32;
 
-pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+// (pid, name, ready) of a player waiting in the lobby
+pub type LobbyPlayer = (PlayerId, String, bool);
 
-#[derive(Clone, Debug)]
+// (pid, x, y) of a player and (id, x, y) of a bullet or enemy in a Snapshot or Delta
+pub type PlayerSnapshot = (PlayerId, u32, u32);
+pub type BulletSnapshot = (String, u32, u32);
+
+pub type Result<T> = std::result::Result<T, ProtocolError>;
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Packet {
-    GameInfo { width: u32, height: u32, pids: Vec<String> }, // sent when starting
+    GameInfo { version: u16, width: u32, height: u32, rules: GameRules, players: Vec<(PlayerId, String)> }, // sent when starting
+    Rejected { reason: String }, // sent instead of JoinAccepted when the join is refused
+    LobbyInfo { top: Vec<LobbyPlayer>, bottom: Vec<LobbyPlayer> }, // sent whenever the lobby changes
+    Countdown { seconds: u8 }, // sent every second once everyone is ready, GameInfo follows
 
-    PlayerJoin(String),
-    PlayerDestroy(String),
+    PlayerJoin { version: u16, name: String, room: String, token: String, spectate: bool }, // an empty room joins any open one,
+                                                                                          // a token from JoinAccepted resumes that player
+    JoinAccepted { pid: PlayerId, room: String, token: String },
+    PlayerDestroy(PlayerId),
 
-    PlayerEvent {pid: String, event: PlayerEvent },
-    PlayerPos { pid: String, x: u32, y: u32 },
+    PlayerEvent {pid: PlayerId, event: PlayerEvent },
+    PlayerPos { pid: PlayerId, x: u32, y: u32, time: u32 }, // time is stamped by the server, in ms
+    PlayerInput { pid: PlayerId, seq: u32, input: Input }, // with Control::Inputs, once every update_interval
+    InputAck { seq: u32, x: u32, y: u32 }, // where the last input the server applied took our own player
 
-    BulletCreate { id: String, x: u32, y: u32 },
+    BulletCreate { id: String, x: u32, y: u32, time: u32 }, // time of the spawn on the server's clock, in ms
     BulletDestroy(String),
     BulletHit,
 
-    EnemyCreate { id: String, x: u32, y: u32 },
+    EnemyCreate { id: String, x: u32, y: u32, time: u32 },
     EnemyDestroy(String),
     EnemyHit,
 
     GameWon,
     GameLost,
+    GamePaused, // a team has no players left, see the server's empty_team setting
+    GameResumed,
+    // the whole field of a team, sent after GameInfo, on reconnect and when a client asks with PlayerEvent::Resync
+    Snapshot { players: Vec<PlayerSnapshot>, bullets: Vec<BulletSnapshot>, enemies: Vec<BulletSnapshot>, score: u32, health: u32 },
+    // bullets and enemies of a team at a server tick: the ones that appeared since the baseline tick or are not
+    // where they would have flown from it (see GameRules::bullet_travel) and the ids of those gone since, or all
+    // of them if the baseline is 0. Sent every update_interval, time is the tick's on the server's clock
+    Delta { tick: u32, time: u32, baseline: u32, bullets: Vec<BulletSnapshot>, enemies: Vec<BulletSnapshot>, removed: Vec<String> },
+    TickAck { tick: u32 }, // the last Delta a client applied, the next ones are sent against it
+    Spectated { top: bool, packet: Box<Packet> }, // a packet of either team, sent to spectators
+
+    Ping, // sent by the server every heartbeat interval
+    Pong, // reply to Ping
+    TimeRequest { client_time: u32 }, // sent by clients to sync their clock, in ms on their own clock
+    TimeReply { client_time: u32, server_time: u32 }, // client_time from the request, server_time when it was answered
 }
 
 impl Packet {
-    pub fn parse(self) -> Vec<u8> {
+    pub(crate) fn id(&self) -> u8 {
+        match self {
+            Self::GameInfo { .. }     => GAME_INFO,
+            Self::Rejected { .. }     => REJECTED,
+            Self::LobbyInfo { .. }    => LOBBY_INFO,
+            Self::Countdown { .. }    => COUNTDOWN,
+            Self::PlayerJoin { .. }   => PLAYER_JOIN,
+            Self::JoinAccepted { .. } => JOIN_ACCEPTED,
+            Self::PlayerDestroy(_)    => PLAYER_DESTROY,
+            Self::PlayerEvent { .. }  => PLAYER_EVENT,
+            Self::PlayerPos { .. }    => PLAYER_POS,
+            Self::PlayerInput { .. }  => PLAYER_INPUT,
+            Self::InputAck { .. }     => INPUT_ACK,
+            Self::BulletCreate { .. } => BULLET_CREATE,
+            Self::BulletDestroy(_)    => BULLET_DESTROY,
+            Self::BulletHit           => BULLET_HIT,
+            Self::EnemyCreate { .. }  => ENEMY_CREATE,
+            Self::EnemyDestroy(_)     => ENEMY_DESTROY,
+            Self::EnemyHit            => ENEMY_HIT,
+            Self::GameWon             => GAME_WON,
+            Self::GameLost            => GAME_LOST,
+            Self::GamePaused          => GAME_PAUSED,
+            Self::GameResumed         => GAME_RESUMED,
+            Self::Snapshot { .. }     => SNAPSHOT,
+            Self::Delta { .. }        => DELTA,
+            Self::TickAck { .. }      => TICK_ACK,
+            Self::Spectated { .. }    => SPECTATED,
+            Self::Ping                => PING,
+            Self::Pong                => PONG,
+            Self::TimeRequest { .. }  => TIME_REQUEST,
+            Self::TimeReply { .. }    => TIME_REPLY,
+        }
+    }
+
+    // whether a packet has to arrive, and in order, over a transport that may lose packets.
+    // The others are soon outdated by the next one of their kind
+    pub fn is_reliable(&self) -> bool {
         match self {
-            Self::GameInfo { width, height, pids } => {
-                let mut raw = BytesMut::new();
-                raw.put_u8(GAME_INFO);
-                raw.put_u32(width);
-                raw.put_u32(height);
-                raw.put_u8(pids.len() as u8);
-                raw.put(pids.join("").as_bytes());
-                raw.to_vec()
-            }
-            Self::PlayerJoin(pid) => {
-                let mut raw = BytesMut::new();
-                raw.put_u8(PLAYER_JOIN);
-                raw.put(pid.as_bytes());
-                raw.to_vec()
+            Self::PlayerPos { .. } | Self::PlayerInput { .. } | Self::InputAck { .. } |
+            Self::Delta { .. } | Self::TickAck { .. } |
+            Self::TimeRequest { .. } | Self::TimeReply { .. } | Self::Ping | Self::Pong => false,
+            Self::Spectated { packet, .. } => packet.is_reliable(),
+            _ => true,
+        }
+    }
+
+    // write the payload of a frame, see PacketCodec for the frame layout
+    pub(crate) fn encode(self, payload: &mut BytesMut) {
+        match self {
+            Self::GameInfo { version, width, height, rules, players } => {
+                payload.put_u16(version);
+                payload.put_u32(width);
+                payload.put_u32(height);
+                payload.put_u32(rules.fire_interval);
+                payload.put_u32(rules.update_interval);
+                payload.put_u32(rules.bullet_interval);
+                payload.put_u32(rules.bullet_speed);
+                payload.put_u32(rules.win_score);
+                payload.put_u8(rules.control as u8);
+                payload.put_u32(rules.move_speed);
+                payload.put_u8(players.len() as u8);
+                for (pid, name) in players {
+                    payload.put_u32(pid);
+                    Self::put_name(payload, &name);
+                }
+            }
+            Self::Rejected { reason } => {
+                payload.put_u16(reason.len() as u16);
+                payload.put(reason.as_bytes());
+            }
+            Self::LobbyInfo { top, bottom } => {
+                for team in [top, bottom] {
+                    payload.put_u8(team.len() as u8);
+                    for (pid, name, ready) in team {
+                        payload.put_u32(pid);
+                        Self::put_name(payload, &name);
+                        payload.put_u8(ready as u8);
+                    }
+                }
+            }
+            Self::Countdown { seconds } => {
+                payload.put_u8(seconds);
+            }
+            Self::PlayerJoin { version, name, room, token, spectate } => {
+                payload.put_u16(version);
+                Self::put_name(payload, &name);
+                Self::put_name(payload, &room);
+                Self::put_name(payload, &token);
+                payload.put_u8(spectate as u8);
+            }
+            Self::JoinAccepted { pid, room, token } => {
+                payload.put_u32(pid);
+                Self::put_name(payload, &room);
+                Self::put_name(payload, &token);
             }
             Self::PlayerDestroy(pid) => {
-                let mut raw = BytesMut::new();
-                raw.put_u8(PLAYER_DESTROY);
-                raw.put(pid.as_bytes());
-                raw.to_vec()
+                payload.put_u32(pid);
             }
             Self::PlayerEvent { pid, event } => {
-                let mut raw = BytesMut::new();
-                raw.put_u8(PLAYER_EVENT);
-                raw.put(pid.as_bytes());
-                raw.put_u8(event.parse());
-                raw.to_vec()
-            }
-            Self::PlayerPos { pid, x, y } => {
-                let mut raw = BytesMut::new();
-                raw.put_u8(PLAYER_POS);
-                raw.put(pid.as_bytes());
-                raw.put_u32(x);
-                raw.put_u32(y);
-                raw.to_vec()
-            }
-            Self::BulletCreate { id, x, y } => {
-                let mut raw = BytesMut::new();
-                raw.put_u8(BULLET_CREATE);
-                raw.put(id.as_bytes());
-                raw.put_u32(x);
-                raw.put_u32(y);
-                raw.to_vec()
-            }
-            Self::BulletDestroy(id) => {
-                let mut raw = BytesMut::new();
-                raw.put_u8(BULLET_DESTROY);
-                raw.put(id.as_bytes());
-                raw.to_vec()
-            }
-            Self::BulletHit => {
-                vec![BULLET_HIT]
-            }
-            Self::EnemyCreate { id, x, y } => {
-                let mut raw = BytesMut::new();
-                raw.put_u8(ENEMY_CREATE);
-                raw.put(id.as_bytes());
-                raw.put_u32(x);
-                raw.put_u32(y);
-                raw.to_vec()
-            }
-            Self::EnemyDestroy(id) => {
-                let mut raw = BytesMut::new();
-                raw.put_u8(ENEMY_DESTROY);
-                raw.put(id.as_bytes());
-                raw.to_vec()
-            }
-            Self::EnemyHit => {
-                vec![ENEMY_HIT]
-            }
-            Self::GameWon => {
-                vec![GAME_WON]
-            }
-            Self::GameLost => {
-                vec![GAME_LOST]
-            }
+                payload.put_u32(pid);
+                payload.put_u8(event.parse());
+            }
+            Self::PlayerPos { pid, x, y, time } => {
+                payload.put_u32(pid);
+                payload.put_u32(x);
+                payload.put_u32(y);
+                payload.put_u32(time);
+            }
+            Self::PlayerInput { pid, seq, input } => {
+                payload.put_u32(pid);
+                payload.put_u32(seq);
+                payload.put_u8(input.bits());
+            }
+            Self::InputAck { seq, x, y } => {
+                payload.put_u32(seq);
+                payload.put_u32(x);
+                payload.put_u32(y);
+            }
+            Self::BulletCreate { id, x, y, time } | Self::EnemyCreate { id, x, y, time } => {
+                payload.put(id.as_bytes());
+                payload.put_u32(x);
+                payload.put_u32(y);
+                payload.put_u32(time);
+            }
+            Self::BulletDestroy(id) | Self::EnemyDestroy(id) => {
+                payload.put(id.as_bytes());
+            }
+            Self::Snapshot { players, bullets, enemies, score, health } => {
+                payload.put_u8(players.len() as u8);
+                for (pid, x, y) in players {
+                    payload.put_u32(pid);
+                    payload.put_u32(x);
+                    payload.put_u32(y);
+                }
+                Self::put_objects(payload, bullets);
+                Self::put_objects(payload, enemies);
+                payload.put_u32(score);
+                payload.put_u32(health);
+            }
+            Self::Delta { tick, time, baseline, bullets, enemies, removed } => {
+                payload.put_u32(tick);
+                payload.put_u32(time);
+                payload.put_u32(baseline);
+                Self::put_objects(payload, bullets);
+                Self::put_objects(payload, enemies);
+                payload.put_u16(removed.len() as u16);
+                for id in removed {
+                    payload.put(id.as_bytes());
+                }
+            }
+            Self::TickAck { tick } => {
+                payload.put_u32(tick);
+            }
+            Self::TimeRequest { client_time } => {
+                payload.put_u32(client_time);
+            }
+            Self::TimeReply { client_time, server_time } => {
+                payload.put_u32(client_time);
+                payload.put_u32(server_time);
+            }
+            Self::Spectated { top, packet } => { // the inner packet without its length, it runs to the end of the frame
+                payload.put_u8(top as u8);
+                payload.put_u8(packet.id());
+                packet.encode(payload);
+            }
+            Self::BulletHit | Self::EnemyHit | Self::GameWon | Self::GameLost |
+            Self::GamePaused | Self::GameResumed | Self::Ping | Self::Pong => (),
         }
     }
 
-    pub fn read_from(stream: &mut impl Read) -> Result<Option<Self>> {
-        let first_byte = Self::read_u8(stream)?;
-        match first_byte {
+    // decode the payload of a frame; trailing bytes are ignored so fields can be appended later
+    pub(crate) fn decode(id: u8, mut payload: &[u8]) -> Result<Self> {
+        let buf = &mut payload;
+        let packet = match id {
             GAME_INFO => {
-                let width = Self::read_u32(stream)?;
-                let height = Self::read_u32(stream)?;
-                let len = Self::read_u8(stream)?;
-                let pids = Self::read_len_str(stream, len as usize, PLAYER_ID_MAX)?;
-                Ok(Some(Self::GameInfo{width, height, pids}))
-            }
+                let version = Self::get_u16(buf)?;
+                let width = Self::get_u32(buf)?;
+                let height = Self::get_u32(buf)?;
+                let rules = GameRules {
+                    fire_interval: Self::get_u32(buf)?,
+                    update_interval: Self::get_u32(buf)?,
+                    bullet_interval: Self::get_u32(buf)?,
+                    bullet_speed: Self::get_u32(buf)?,
+                    win_score: Self::get_u32(buf)?,
+                    control: match Self::get_u8(buf)? {
+                        0 => Control::Positions,
+                        _ => Control::Inputs,
+                    },
+                    move_speed: Self::get_u32(buf)?,
+                };
+                let len = Self::get_u8(buf)?;
+                let mut players = Vec::with_capacity(len as usize);
+                for _ in 0..len {
+                    let pid = Self::get_u32(buf)?;
+                    players.push((pid, Self::get_name(buf)?));
+                }
+                Self::GameInfo{version, width, height, rules, players}
+            }
+            REJECTED => {
+                let len = Self::get_u16(buf)?;
+                Self::Rejected{reason: Self::get_str(buf, len as usize)?}
+            }
+            LOBBY_INFO => {
+                let top = Self::get_lobby_team(buf)?;
+                let bottom = Self::get_lobby_team(buf)?;
+                Self::LobbyInfo{top, bottom}
+            }
+            COUNTDOWN => Self::Countdown{seconds: Self::get_u8(buf)?},
             PLAYER_JOIN => {
-                let pid = Self::read_str(stream, PLAYER_ID_MAX)?;
-                Ok(Some(Self::PlayerJoin(pid)))
-            }
-            PLAYER_DESTROY => {
-                let pid = Self::read_str(stream, PLAYER_ID_MAX)?;
-                Ok(Some(Self::PlayerDestroy(pid)))
-            }
+                let version = Self::get_u16(buf)?;
+                let name = Self::get_name(buf)?;
+                let room = Self::get_name(buf)?;
+                let token = Self::get_name(buf)?;
+                let spectate = Self::get_u8(buf)? != 0;
+                Self::PlayerJoin{version, name, room, token, spectate}
+            }
+            JOIN_ACCEPTED => {
+                let pid = Self::get_u32(buf)?;
+                let room = Self::get_name(buf)?;
+                let token = Self::get_name(buf)?;
+                Self::JoinAccepted{pid, room, token}
+            }
+            PLAYER_DESTROY => Self::PlayerDestroy(Self::get_u32(buf)?),
             PLAYER_EVENT => {
-                let pid = Self::read_str(stream, PLAYER_ID_MAX)?;
-                let event = PlayerEvent::get(Self::read_u8(stream)?).unwrap();
-                Ok(Some(Self::PlayerEvent{pid, event}))
+                let pid = Self::get_u32(buf)?;
+                let event = PlayerEvent::get(Self::get_u8(buf)?)?;
+                Self::PlayerEvent{pid, event}
             }
             PLAYER_POS => {
-                let pid = Self::read_str(stream, PLAYER_ID_MAX)?;
-                let x = Self::read_u32(stream)?;
-                let y = Self::read_u32(stream)?;
-                Ok(Some(Self::PlayerPos{pid, x, y}))
+                let pid = Self::get_u32(buf)?;
+                let x = Self::get_u32(buf)?;
+                let y = Self::get_u32(buf)?;
+                let time = Self::get_u32(buf)?;
+                Self::PlayerPos{pid, x, y, time}
+            }
+            PLAYER_INPUT => {
+                let pid = Self::get_u32(buf)?;
+                let seq = Self::get_u32(buf)?;
+                let input = Input::from_bits(Self::get_u8(buf)?);
+                Self::PlayerInput{pid, seq, input}
+            }
+            INPUT_ACK => {
+                let seq = Self::get_u32(buf)?;
+                let x = Self::get_u32(buf)?;
+                let y = Self::get_u32(buf)?;
+                Self::InputAck{seq, x, y}
             }
             BULLET_CREATE => {
-                let id = Self::read_str(stream, BULLET_ID_LEN)?; 
-                let x = Self::read_u32(stream)?;
-                let y = Self::read_u32(stream)?;
-                Ok(Some(Self::BulletCreate{id, x, y}))
-            }
-            BULLET_DESTROY => {
-                let id = Self::read_str(stream, BULLET_ID_LEN)?;
-                Ok(Some(Self::BulletDestroy(id)))
-            }
-            BULLET_HIT => {
-                Ok(Some(Self::BulletHit))
-            }
+                let id = Self::get_str(buf, BULLET_ID_LEN)?;
+                let x = Self::get_u32(buf)?;
+                let y = Self::get_u32(buf)?;
+                let time = Self::get_u32(buf)?;
+                Self::BulletCreate{id, x, y, time}
+            }
+            BULLET_DESTROY => Self::BulletDestroy(Self::get_str(buf, BULLET_ID_LEN)?),
+            BULLET_HIT => Self::BulletHit,
             ENEMY_CREATE => {
-                let id = Self::read_str(stream, BULLET_ID_LEN)?; 
-                let x = Self::read_u32(stream)?;
-                let y = Self::read_u32(stream)?;
-                Ok(Some(Self::EnemyCreate{id, x, y}))
-            }
-            ENEMY_DESTROY => {
-                let id = Self::read_str(stream, BULLET_ID_LEN)?;
-                Ok(Some(Self::EnemyDestroy(id)))
-            }
-            ENEMY_HIT => {
-                Ok(Some(Self::EnemyHit))
-            }
-            GAME_WON => {
-                Ok(Some(Self::GameWon))
-            }
-            GAME_LOST => {
-                Ok(Some(Self::GameLost))
-            }
-            _ => Err(String::from("invalid first byte").into()),
+                let id = Self::get_str(buf, BULLET_ID_LEN)?;
+                let x = Self::get_u32(buf)?;
+                let y = Self::get_u32(buf)?;
+                let time = Self::get_u32(buf)?;
+                Self::EnemyCreate{id, x, y, time}
+            }
+            ENEMY_DESTROY => Self::EnemyDestroy(Self::get_str(buf, BULLET_ID_LEN)?),
+            ENEMY_HIT => Self::EnemyHit,
+            GAME_WON => Self::GameWon,
+            GAME_LOST => Self::GameLost,
+            GAME_PAUSED => Self::GamePaused,
+            GAME_RESUMED => Self::GameResumed,
+            SNAPSHOT => {
+                let len = Self::get_u8(buf)?;
+                let mut players = Vec::with_capacity(len as usize);
+                for _ in 0..len {
+                    let pid = Self::get_u32(buf)?;
+                    let x = Self::get_u32(buf)?;
+                    let y = Self::get_u32(buf)?;
+                    players.push((pid, x, y));
+                }
+                let bullets = Self::get_objects(buf)?;
+                let enemies = Self::get_objects(buf)?;
+                let score = Self::get_u32(buf)?;
+                let health = Self::get_u32(buf)?;
+                Self::Snapshot{players, bullets, enemies, score, health}
+            }
+            DELTA => {
+                let tick = Self::get_u32(buf)?;
+                let time = Self::get_u32(buf)?;
+                let baseline = Self::get_u32(buf)?;
+                let bullets = Self::get_objects(buf)?;
+                let enemies = Self::get_objects(buf)?;
+                let len = Self::get_u16(buf)?;
+                let mut removed = Vec::with_capacity(len as usize);
+                for _ in 0..len {
+                    removed.push(Self::get_str(buf, BULLET_ID_LEN)?);
+                }
+                Self::Delta{tick, time, baseline, bullets, enemies, removed}
+            }
+            TICK_ACK => Self::TickAck{tick: Self::get_u32(buf)?},
+            SPECTATED => {
+                let top = Self::get_u8(buf)? != 0;
+                let id = Self::get_u8(buf)?;
+                if id == SPECTATED {
+                    return Err(ProtocolError::UnknownPacket(id)); // no nesting
+                }
+                Self::Spectated{top, packet: Box::new(Self::decode(id, buf)?)}
+            }
+            PING => Self::Ping,
+            PONG => Self::Pong,
+            TIME_REQUEST => Self::TimeRequest{client_time: Self::get_u32(buf)?},
+            TIME_REPLY => {
+                let client_time = Self::get_u32(buf)?;
+                let server_time = Self::get_u32(buf)?;
+                Self::TimeReply{client_time, server_time}
+            }
+            _ => return Err(ProtocolError::UnknownPacket(id)),
+        };
+        Ok(packet)
+    }
+
+    fn check_remaining(buf: &[u8], len: usize) -> Result<()> {
+        if buf.remaining() < len {
+            return Err(ProtocolError::Truncated);
         }
+        Ok(())
     }
 
-    // for consistency
-    fn read_u8(stream: &mut impl Read) -> Result<u8> {
-        Ok(stream.read_u8()?)
+    fn get_u8(buf: &mut &[u8]) -> Result<u8> {
+        Self::check_remaining(buf, 1)?;
+        Ok(buf.get_u8())
     }
 
-    fn read_u32(stream: &mut impl Read) -> Result<u32> {
-        Ok(stream.read_u32::<byteorder::BigEndian>()?)
+    fn get_u16(buf: &mut &[u8]) -> Result<u16> {
+        Self::check_remaining(buf, 2)?;
+        Ok(buf.get_u16())
     }
 
-    // read string of length
-    fn read_str(stream: &mut impl Read, len: usize) -> Result<String> {
-        let mut buf = vec![0; len];
-        stream.read_exact(&mut buf)?;
-        Ok(String::from_utf8_lossy(&buf).to_string())
+    fn get_u32(buf: &mut &[u8]) -> Result<u32> {
+        Self::check_remaining(buf, 4)?;
+        Ok(buf.get_u32())
     }
 
-    // read consecutive strings of size s into vector of len
-    fn read_len_str(stream: &mut impl Read, len: usize, s: usize) -> Result<Vec<String>> {
-        let mut vec = Vec::with_capacity(len);
-        for _ in 0..len {
-            vec.push(Self::read_str(stream, s)?);
-        }
-        Ok(vec)
+    // read string of length
+    fn get_str(buf: &mut &[u8], len: usize) -> Result<String> {
+        Self::check_remaining(buf, len)?;
+        let s = String::from_utf8(buf[..len].to_vec()).map_err(|_| ProtocolError::InvalidUtf8)?;
+        buf.advance(len);
+        Ok(s)
     }
 
-    pub async fn async_read_from<T: AsyncBufRead + Unpin>(stream: &mut T) -> Result<Option<Self>> {
-        let first_byte = Self::async_read_u8(stream).await?;
-        match first_byte {
-            GAME_INFO => {
-                let width = Self::async_read_u32(stream).await?;
-                let height = Self::async_read_u32(stream).await?;
-                let len = Self::async_read_u8(stream).await?;
-                let pids = Self::async_read_len_str(stream, len as usize, PLAYER_ID_MAX).await?;
-                Ok(Some(Self::GameInfo{width, height, pids}))
-            }
-            PLAYER_JOIN => {
-                let pid = Self::async_read_str(stream, PLAYER_ID_MAX).await?;
-                Ok(Some(Self::PlayerJoin(pid)))
-            }
-            PLAYER_DESTROY => {
-                let pid = Self::async_read_str(stream, PLAYER_ID_MAX).await?;
-                Ok(Some(Self::PlayerDestroy(pid)))
-            }
-            PLAYER_EVENT => {
-                let pid = Self::async_read_str(stream, PLAYER_ID_MAX).await?;
-                let event = PlayerEvent::get(Self::async_read_u8(stream).await?).unwrap();
-                Ok(Some(Self::PlayerEvent{pid, event}))
-            }
-            PLAYER_POS => {
-                let pid = Self::async_read_str(stream, PLAYER_ID_MAX).await?;
-                let x = Self::async_read_u32(stream).await?;
-                let y = Self::async_read_u32(stream).await?;
-                Ok(Some(Self::PlayerPos{pid, x, y}))
-            }
-            BULLET_CREATE => {
-                let id = Self::async_read_str(stream, BULLET_ID_LEN).await?;
-                let x = Self::async_read_u32(stream).await?;
-                let y = Self::async_read_u32(stream).await?;
-                Ok(Some(Self::BulletCreate{id, x, y}))
-            }
-            BULLET_DESTROY => {
-                let id = Self::async_read_str(stream, BULLET_ID_LEN).await?;
-                Ok(Some(Self::BulletDestroy(id)))
-            }
-            BULLET_HIT => {
-                Ok(Some(Self::BulletHit))
-            }
-            ENEMY_CREATE => {
-                let id = Self::async_read_str(stream, BULLET_ID_LEN).await?;
-                let x = Self::async_read_u32(stream).await?;
-                let y = Self::async_read_u32(stream).await?;
-                Ok(Some(Self::EnemyCreate{id, x, y}))
-            }
-            ENEMY_DESTROY => {
-                let id = Self::async_read_str(stream, BULLET_ID_LEN).await?;
-                Ok(Some(Self::EnemyDestroy(id)))
-            }
-            ENEMY_HIT => {
-                Ok(Some(Self::EnemyHit))
-            }
-            GAME_WON => {
-                Ok(Some(Self::GameWon))
-            }
-            GAME_LOST => {
-                Ok(Some(Self::GameLost))
-            }
-            _ => Err(String::from("invalid first byte").into()),
-        }
+    // names of players and rooms, and session tokens, are prefixed with their length in bytes (u8)
+    fn put_name(payload: &mut BytesMut, name: &str) {
+        let name = truncate_name(name);
+        payload.put_u8(name.len() as u8);
+        payload.put(name.as_bytes());
     }
 
-    // for consistency
-    async fn async_read_u8<T: AsyncBufRead + Unpin>(stream: &mut T) -> Result<u8> {
-        Ok(stream.read_u8().await?)
+    fn get_name(buf: &mut &[u8]) -> Result<String> {
+        let len = Self::get_u8(buf)?;
+        Self::get_str(buf, len as usize)
     }
 
-    async fn async_read_u32<T: AsyncBufRead + Unpin>(stream: &mut T) -> Result<u32> {
-        Ok(stream.read_u32().await?)
+    fn get_lobby_team(buf: &mut &[u8]) -> Result<Vec<LobbyPlayer>> {
+        let len = Self::get_u8(buf)?;
+        let mut players = Vec::with_capacity(len as usize);
+        for _ in 0..len {
+            let pid = Self::get_u32(buf)?;
+            let name = Self::get_name(buf)?;
+            players.push((pid, name, Self::get_u8(buf)? != 0));
+        }
+        Ok(players)
     }
 
-    // read string of length
-    async fn async_read_str<T: AsyncBufRead + Unpin>(stream: &mut T, len: usize) -> Result<String> {
-        let mut buf = vec![0; len];
-        stream.read_exact(&mut buf).await?;
-        Ok(String::from_utf8_lossy(&buf).to_string())
+    // bullets and enemies are prefixed with their count (u16), their ids have a fixed length
+    fn put_objects(payload: &mut BytesMut, objects: Vec<BulletSnapshot>) {
+        payload.put_u16(objects.len() as u16);
+        for (id, x, y) in objects {
+            payload.put(id.as_bytes());
+            payload.put_u32(x);
+            payload.put_u32(y);
+        }
     }
 
-    // read consecutive strings of size s into vector of len
-    async fn async_read_len_str<T: AsyncBufRead + Unpin>(stream: &mut T, len: usize, s: usize) -> Result<Vec<String>> {
-        let mut vec = Vec::with_capacity(len);
+    fn get_objects(buf: &mut &[u8]) -> Result<Vec<BulletSnapshot>> {
+        let len = Self::get_u16(buf)?;
+        let mut objects = Vec::with_capacity(len as usize);
         for _ in 0..len {
-            vec.push(Self::async_read_str(stream, s).await?);
+            let id = Self::get_str(buf, BULLET_ID_LEN)?;
+            let x = Self::get_u32(buf)?;
+            let y = Self::get_u32(buf)?;
+            objects.push((id, x, y));
         }
-        Ok(vec)
+        Ok(objects)
     }
 }
 
-#[derive(Clone, Debug)]
+// cut a display name down to PLAYER_NAME_MAX chars without splitting a character
+pub fn truncate_name(name: &str) -> &str {
+    match name.char_indices().nth(PLAYER_NAME_MAX) {
+        Some((i, _)) => &name[..i],
+        None => name,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PlayerEvent {
     Fire,
     Exit,
+    Ready, // toggles the ready flag while in the lobby
+    Resync, // asks for a Snapshot
 }
 
 impl PlayerEvent {
@@ -324,6 +526,8 @@ impl PlayerEvent {
         match self {
             Self::Fire      => 0,
             Self::Exit      => 1,
+            Self::Ready     => 2,
+            Self::Resync    => 3,
         }
     }
 
@@ -331,7 +535,35 @@ impl PlayerEvent {
         match value {
             0 => Ok(Self::Fire),
             1 => Ok(Self::Exit),
-            _ => Err(String::from("invalid event").into()),
+            2 => Ok(Self::Ready),
+            3 => Ok(Self::Resync),
+            _ => Err(ProtocolError::InvalidEvent(value)),
         }
     }
-}
\ No newline at end of file
+}
+
+// the buttons a player holds, see Control::Inputs
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Input {
+    pub left: bool,
+    pub right: bool,
+    pub boost: bool,
+    pub fire: bool,
+}
+
+impl Input {
+    pub fn bits(&self) -> u8 {
+        self.left as u8 | (self.right as u8) << 1 | (self.boost as u8) << 2 | (self.fire as u8) << 3
+    }
+
+    // unknown bits are ignored
+    pub fn from_bits(bits: u8) -> Self {
+        Input {
+            left: bits & 1 != 0,
+            right: bits & 1 << 1 != 0,
+            boost: bits & 1 << 2 != 0,
+            fire: bits & 1 << 3 != 0,
+        }
+    }
+}
@@ -0,0 +1,267 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt,
+    mem::{self, Discriminant},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{Packet, PacketCodec, PlayerId, ProtocolError, Result};
+
+// datagram header: kind (u8), sequence number (u32), ack (u32), followed by one frame of the PacketCodec
+const DATAGRAM_HEADER_LEN: usize = 9;
+const COOKIE_LEN: usize = 8; // a u64 after the header, see Link::cookie
+pub const MAX_DATAGRAM_LEN: usize = 65507; // what fits into one UDP datagram
+pub const RESEND_AFTER: Duration = Duration::from_millis(200); // reliable packets not acked by then go out again
+const MAX_EARLY: usize = 1024; // reliable packets kept while waiting for a missing one before them
+
+// datagram kinds
+const UNRELIABLE: u8 = 0;
+const RELIABLE: u8 = 1;
+const ACK: u8 = 2; // no packet, sent back for every reliable datagram
+const COOKIE: u8 = 3; // no packet, the server's answer to a datagram that would open a connection
+const OPEN: u8 = 4; // the first reliable packet again, with the cookie the server answered it with
+
+/// How clients and the server exchange packets.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(rename_all = "lowercase"))]
+pub enum Transport {
+    #[default]
+    Tcp,
+    Udp, // datagrams through a Link
+}
+
+impl FromStr for Transport {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "tcp" => Ok(Self::Tcp),
+            "udp" => Ok(Self::Udp),
+            _ => Err(format!("unknown transport '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Tcp => write!(f, "tcp"),
+            Self::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+/// One end of a connection over UDP.
+///
+/// Packets that are soon outdated (see `Packet::is_reliable`) are sent once, and one that arrives
+/// after a newer one of the same stream is dropped (see `stream`). The others are numbered, sent again until the peer acks them
+/// and handed out in order. The link does no I/O: it turns packets into datagrams and back.
+///
+/// The server keeps nothing for a datagram that opens a connection but answers it with a cookie,
+/// the link is only made once the client sends its first packet again with that cookie. So a
+/// forged sender address gets a datagram smaller than its own, and no state on the server.
+pub struct Link {
+    codec: PacketCodec,
+    sent: u32, // sequence number of the last reliable packet sent
+    unacked: VecDeque<(u32, BytesMut, Instant)>, // reliable frames, with when they last went out
+    received: u32, // reliable packets up to this one were handed out
+    early: BTreeMap<u32, BytesMut>, // reliable frames that overtook a missing one
+    cookie: Option<u64>, // what the server answered our first packet with
+    unreliable_sent: u32,
+    unreliable_received: HashMap<Stream, u32>, // the newest unreliable packet of each stream so far
+    ready: VecDeque<Result<Packet>>,
+}
+
+impl Link {
+    pub fn new(codec: PacketCodec) -> Link {
+        Link {
+            codec,
+            sent: 0,
+            unacked: VecDeque::new(),
+            received: 0,
+            early: BTreeMap::new(),
+            cookie: None,
+            unreliable_sent: 0,
+            unreliable_received: HashMap::new(),
+            ready: VecDeque::new(),
+        }
+    }
+
+    /// Returns the datagram that carries a packet.
+    pub fn send(&mut self, packet: Packet, now: Instant) -> Result<BytesMut> {
+        let reliable = packet.is_reliable();
+        let mut frame = BytesMut::new();
+        self.codec.encode(packet, &mut frame)?;
+        if DATAGRAM_HEADER_LEN + frame.len() > MAX_DATAGRAM_LEN {
+            return Err(ProtocolError::PacketTooLarge);
+        }
+        if reliable {
+            self.sent += 1;
+            let datagram = Self::datagram(RELIABLE, self.sent, self.received, &frame);
+            self.unacked.push_back((self.sent, frame, now));
+            Ok(datagram)
+        } else {
+            self.unreliable_sent += 1;
+            Ok(Self::datagram(UNRELIABLE, self.unreliable_sent, self.received, &frame))
+        }
+    }
+
+    /// Takes in a datagram from the peer, its packet is then handed out by `recv` when its turn comes.
+    /// Returns the datagram to send back: the ack of a reliable packet, or our first packet with a cookie.
+    pub fn receive(&mut self, mut datagram: &[u8]) -> Result<Option<BytesMut>> {
+        if datagram.len() < DATAGRAM_HEADER_LEN {
+            return Err(ProtocolError::Truncated);
+        }
+        let (kind, seq, ack) = (datagram.get_u8(), datagram.get_u32(), datagram.get_u32());
+        if (kind == COOKIE || kind == OPEN) && datagram.len() < COOKIE_LEN {
+            return Err(ProtocolError::Truncated);
+        }
+        self.unacked.retain(|(sent, _, _)| *sent > ack);
+
+        match kind {
+            ACK => Ok(None),
+            COOKIE => {
+                let cookie = datagram.get_u64();
+                let received = self.received;
+                match self.unacked.front_mut() {
+                    Some((1, frame, sent_at)) if self.cookie.is_none() => {
+                        self.cookie = Some(cookie);
+                        *sent_at = Instant::now();
+                        Ok(Some(Self::opening(cookie, received, frame)))
+                    }
+                    _ => Ok(None), // late, the connection is open
+                }
+            }
+            OPEN => {
+                datagram.advance(COOKIE_LEN); // checked by the server before it made this link
+                self.receive_reliable(seq, datagram)
+            }
+            UNRELIABLE => {
+                let packet = self.decode(datagram);
+                let outdated = match &packet {
+                    Ok(packet) => {
+                        let newest = self.unreliable_received.entry(stream(packet)).or_insert(0);
+                        let outdated = seq <= *newest;
+                        *newest = seq.max(*newest);
+                        outdated
+                    }
+                    Err(_) => false,
+                };
+                if !outdated {
+                    self.ready.push_back(packet);
+                }
+                Ok(None)
+            }
+            RELIABLE => self.receive_reliable(seq, datagram),
+            kind => Err(ProtocolError::InvalidDatagram(kind)),
+        }
+    }
+
+    /// Whether a datagram opens a connection: the first reliable packet of a link, sent again until
+    /// it is acked. It is answered with `Link::cookie`, anything else from a peer we do not know
+    /// is left over from an older connection.
+    pub fn opens(datagram: &[u8]) -> bool {
+        datagram.len() > DATAGRAM_HEADER_LEN && datagram[0] == RELIABLE && datagram[1..5] == 1u32.to_be_bytes()
+    }
+
+    /// The datagram that answers one that opens a connection. The cookie should be one only the
+    /// server can work out from the peer's address, it has to come back with `Link::echoed_cookie`.
+    pub fn cookie(cookie: u64) -> BytesMut {
+        let mut datagram = Self::datagram(COOKIE, 0, 0, &[]);
+        datagram.put_u64(cookie);
+        datagram
+    }
+
+    /// The cookie a peer sent back with its first packet, the link is made once it is right.
+    pub fn echoed_cookie(datagram: &[u8]) -> Option<u64> {
+        if datagram.len() > DATAGRAM_HEADER_LEN + COOKIE_LEN && datagram[0] == OPEN {
+            Some((&datagram[DATAGRAM_HEADER_LEN..]).get_u64())
+        } else {
+            None
+        }
+    }
+
+    /// Returns the next packet in order, or `None` until another one arrives.
+    pub fn recv(&mut self) -> Option<Result<Packet>> {
+        self.ready.pop_front()
+    }
+
+    /// Returns the reliable datagrams the peer did not ack within `RESEND_AFTER`.
+    pub fn resend(&mut self, now: Instant) -> Vec<BytesMut> {
+        let (received, cookie) = (self.received, self.cookie);
+        self.unacked.iter_mut()
+            .filter(|(_, _, sent_at)| now.saturating_duration_since(*sent_at) >= RESEND_AFTER)
+            .map(|(seq, frame, sent_at)| {
+                *sent_at = now;
+                match cookie {
+                    Some(cookie) if *seq == 1 => Self::opening(cookie, received, frame),
+                    _ => Self::datagram(RELIABLE, *seq, received, frame),
+                }
+            })
+            .collect()
+    }
+
+    /// Whether the peer acked every reliable packet sent so far.
+    pub fn is_idle(&self) -> bool {
+        self.unacked.is_empty()
+    }
+
+    fn receive_reliable(&mut self, seq: u32, frame: &[u8]) -> Result<Option<BytesMut>> {
+        if seq > self.received && (seq == self.received + 1 || self.early.len() < MAX_EARLY) {
+            self.early.insert(seq, BytesMut::from(frame));
+        }
+        while let Some(frame) = self.early.remove(&(self.received + 1)) {
+            self.received += 1;
+            let packet = self.decode(&frame);
+            self.ready.push_back(packet);
+        }
+        // duplicates are acked again, the first ack may have been lost
+        Ok(Some(Self::datagram(ACK, 0, self.received, &[])))
+    }
+
+    fn opening(cookie: u64, ack: u32, frame: &[u8]) -> BytesMut {
+        let mut datagram = BytesMut::with_capacity(DATAGRAM_HEADER_LEN + COOKIE_LEN + frame.len());
+        datagram.put_u8(OPEN);
+        datagram.put_u32(1);
+        datagram.put_u32(ack);
+        datagram.put_u64(cookie);
+        datagram.put(frame);
+        datagram
+    }
+
+    fn datagram(kind: u8, seq: u32, ack: u32, frame: &[u8]) -> BytesMut {
+        let mut datagram = BytesMut::with_capacity(DATAGRAM_HEADER_LEN + frame.len());
+        datagram.put_u8(kind);
+        datagram.put_u32(seq);
+        datagram.put_u32(ack);
+        datagram.put(frame);
+        datagram
+    }
+
+    // a datagram holds exactly one frame
+    fn decode(&mut self, frame: &[u8]) -> Result<Packet> {
+        match self.codec.decode(&mut BytesMut::from(frame))? {
+            Some(packet) => packet,
+            None => Err(ProtocolError::Truncated),
+        }
+    }
+}
+
+// what an unreliable packet is newer than: packets of the same kind, for positions of the same
+// player, and for a spectator of the same side. One player's position never hides another's
+type Stream = (Option<bool>, Discriminant<Packet>, PlayerId);
+
+fn stream(packet: &Packet) -> Stream {
+    match packet {
+        Packet::Spectated { top, packet } => {
+            let (_, kind, pid) = stream(packet);
+            (Some(*top), kind, pid)
+        }
+        Packet::PlayerPos { pid, .. } => (None, mem::discriminant(packet), *pid),
+        _ => (None, mem::discriminant(packet), 0),
+    }
+}
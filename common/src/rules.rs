@@ -0,0 +1,100 @@
+use std::{fmt, str::FromStr, time::Duration};
+
+use crate::Input;
+
+const PLAYER_FIRE_INTERVAL:   u32 = 300; // in ms
+const PLAYER_UPDATE_INTERVAL: u32 = 50;  // in ms
+const PLAYER_MOVE_SPEED:      u32 = 480; // in pixels per second, boosted 2.5 times
+const BULLET_UPDATE_INTERVAL: u32 = 8;   // in ms
+const BULLET_UPDATE_MOVEMENT: u32 = 2;   // in pixels
+const GAME_END_SCORE:         u32 = 100;
+
+/// Gameplay settings chosen by the server and sent to the clients in `GameInfo`.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GameRules {
+    pub fire_interval: u32,   // in ms, minimum time between two shots
+    pub update_interval: u32, // in ms, minimum time between two position updates
+    pub bullet_interval: u32, // in ms, time between two bullet movements
+    pub bullet_speed: u32,    // in pixels per bullet movement
+    pub win_score: u32,
+    pub control: Control,
+    pub move_speed: u32,      // in pixels per second, when the server moves the players
+}
+
+/// Who moves the players.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(rename_all = "lowercase"))]
+pub enum Control {
+    #[default]
+    Positions, // clients move their player and send where it is
+    Inputs, // clients send the buttons they hold every update_interval, the server moves the players by them
+}
+
+impl FromStr for Control {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "positions" => Ok(Self::Positions),
+            "inputs" => Ok(Self::Inputs),
+            _ => Err(format!("unknown control '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Control {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Positions => write!(f, "positions"),
+            Self::Inputs => write!(f, "inputs"),
+        }
+    }
+}
+
+impl Default for GameRules {
+    fn default() -> Self {
+        GameRules {
+            fire_interval: PLAYER_FIRE_INTERVAL,
+            update_interval: PLAYER_UPDATE_INTERVAL,
+            bullet_interval: BULLET_UPDATE_INTERVAL,
+            bullet_speed: BULLET_UPDATE_MOVEMENT,
+            win_score: GAME_END_SCORE,
+            control: Control::Positions,
+            move_speed: PLAYER_MOVE_SPEED,
+        }
+    }
+}
+
+impl GameRules {
+    pub fn fire_interval(&self) -> Duration {
+        Duration::from_millis(self.fire_interval as u64)
+    }
+
+    pub fn update_interval(&self) -> Duration {
+        Duration::from_millis(self.update_interval as u64)
+    }
+
+    pub fn bullet_interval(&self) -> Duration {
+        Duration::from_millis(self.bullet_interval as u64)
+    }
+
+    // in pixels per ms
+    pub fn bullet_velocity(&self) -> f32 {
+        self.bullet_speed as f32 / self.bullet_interval.max(1) as f32
+    }
+
+    // pixels a bullet or enemy flies in some ms, the same on the server and on the client:
+    // a Delta leaves out the ones that are about where this puts them
+    pub fn bullet_travel(&self, ms: u32) -> u32 {
+        (ms as u64 * self.bullet_speed as u64 / self.bullet_interval.max(1) as u64).min(u32::MAX as u64) as u32
+    }
+
+    // pixels a player moves along x in one update_interval while holding the input,
+    // the same on the server and on the client
+    pub fn step(&self, input: Input) -> i64 {
+        let speed = if input.boost { self.move_speed as i64 * 5 / 2 } else { self.move_speed as i64 };
+        let direction = input.right as i64 - input.left as i64;
+        direction * speed * self.update_interval as i64 / 1000
+    }
+}
@@ -0,0 +1,214 @@
+use std::io::Cursor;
+
+use bytes::BytesMut;
+use futures::StreamExt;
+use proptest::prelude::*;
+use tokio_util::codec::{Decoder, Encoder, FramedRead};
+
+use common::*;
+
+fn formats() -> Vec<WireFormat> {
+    vec![
+        WireFormat::Binary,
+        #[cfg(feature = "serde")]
+        WireFormat::Json,
+    ]
+}
+
+fn name() -> impl Strategy<Value = String> {
+    "\\PC{1,32}"
+}
+
+fn room() -> impl Strategy<Value = String> {
+    "\\PC{0,32}"
+}
+
+fn token() -> impl Strategy<Value = String> {
+    "[A-Za-z0-9_-]{0,32}"
+}
+
+fn object_id() -> impl Strategy<Value = String> {
+    prop::string::string_regex(&format!("[A-Za-z0-9_-]{{{}}}", BULLET_ID_LEN)).unwrap()
+}
+
+fn player_event() -> impl Strategy<Value = PlayerEvent> {
+    prop_oneof![Just(PlayerEvent::Fire), Just(PlayerEvent::Exit), Just(PlayerEvent::Ready), Just(PlayerEvent::Resync)]
+}
+
+fn lobby_team() -> impl Strategy<Value = Vec<LobbyPlayer>> {
+    prop::collection::vec((any::<PlayerId>(), name(), any::<bool>()), 0..8)
+}
+
+fn objects() -> impl Strategy<Value = Vec<BulletSnapshot>> {
+    prop::collection::vec((object_id(), any::<u32>(), any::<u32>()), 0..16)
+}
+
+fn game_rules() -> impl Strategy<Value = GameRules> {
+    let control = prop_oneof![Just(Control::Positions), Just(Control::Inputs)];
+    (any::<u32>(), any::<u32>(), any::<u32>(), any::<u32>(), any::<u32>(), control, any::<u32>()).prop_map(
+        |(fire_interval, update_interval, bullet_interval, bullet_speed, win_score, control, move_speed)| GameRules {
+            fire_interval, update_interval, bullet_interval, bullet_speed, win_score, control, move_speed,
+        },
+    )
+}
+
+fn input() -> impl Strategy<Value = Input> {
+    any::<u8>().prop_map(|bits| Input::from_bits(bits & 0x0F))
+}
+
+fn delta() -> impl Strategy<Value = Packet> {
+    (any::<u32>(), any::<u32>(), any::<u32>(), objects(), objects(), prop::collection::vec(object_id(), 0..16))
+        .prop_map(|(tick, time, baseline, bullets, enemies, removed)| Packet::Delta { tick, time, baseline, bullets, enemies, removed })
+}
+
+// packets of a team's field, as forwarded to spectators
+fn field_packet() -> impl Strategy<Value = Packet> {
+    prop_oneof![
+        (any::<PlayerId>(), any::<u32>(), any::<u32>(), any::<u32>()).prop_map(|(pid, x, y, time)| Packet::PlayerPos { pid, x, y, time }),
+        (object_id(), any::<u32>(), any::<u32>(), any::<u32>()).prop_map(|(id, x, y, time)| Packet::EnemyCreate { id, x, y, time }),
+        object_id().prop_map(Packet::BulletDestroy),
+        Just(Packet::BulletHit),
+        Just(Packet::GameWon),
+        (prop::collection::vec((any::<PlayerId>(), any::<u32>(), any::<u32>()), 0..8), objects(), objects(), any::<u32>(), any::<u32>())
+            .prop_map(|(players, bullets, enemies, score, health)| Packet::Snapshot { players, bullets, enemies, score, health }),
+        delta(),
+    ]
+}
+
+// one arm per Packet variant
+fn packet() -> impl Strategy<Value = Packet> {
+    prop_oneof![
+        (any::<u16>(), any::<u32>(), any::<u32>(), game_rules(), prop::collection::vec((any::<PlayerId>(), name()), 0..8))
+            .prop_map(|(version, width, height, rules, players)| Packet::GameInfo { version, width, height, rules, players }),
+        "\\PC{0,200}".prop_map(|reason| Packet::Rejected { reason }),
+        (lobby_team(), lobby_team()).prop_map(|(top, bottom)| Packet::LobbyInfo { top, bottom }),
+        any::<u8>().prop_map(|seconds| Packet::Countdown { seconds }),
+        (any::<u16>(), name(), room(), token(), any::<bool>())
+            .prop_map(|(version, name, room, token, spectate)| Packet::PlayerJoin { version, name, room, token, spectate }),
+        (any::<PlayerId>(), room(), token()).prop_map(|(pid, room, token)| Packet::JoinAccepted { pid, room, token }),
+        any::<PlayerId>().prop_map(Packet::PlayerDestroy),
+        (any::<PlayerId>(), player_event()).prop_map(|(pid, event)| Packet::PlayerEvent { pid, event }),
+        (any::<PlayerId>(), any::<u32>(), any::<u32>(), any::<u32>()).prop_map(|(pid, x, y, time)| Packet::PlayerPos { pid, x, y, time }),
+        (any::<PlayerId>(), any::<u32>(), input()).prop_map(|(pid, seq, input)| Packet::PlayerInput { pid, seq, input }),
+        (any::<u32>(), any::<u32>(), any::<u32>()).prop_map(|(seq, x, y)| Packet::InputAck { seq, x, y }),
+        (object_id(), any::<u32>(), any::<u32>(), any::<u32>()).prop_map(|(id, x, y, time)| Packet::BulletCreate { id, x, y, time }),
+        object_id().prop_map(Packet::BulletDestroy),
+        Just(Packet::BulletHit),
+        (object_id(), any::<u32>(), any::<u32>(), any::<u32>()).prop_map(|(id, x, y, time)| Packet::EnemyCreate { id, x, y, time }),
+        object_id().prop_map(Packet::EnemyDestroy),
+        Just(Packet::EnemyHit),
+        Just(Packet::GameWon),
+        Just(Packet::GameLost),
+        Just(Packet::GamePaused),
+        Just(Packet::GameResumed),
+        (prop::collection::vec((any::<PlayerId>(), any::<u32>(), any::<u32>()), 0..8), objects(), objects(), any::<u32>(), any::<u32>())
+            .prop_map(|(players, bullets, enemies, score, health)| Packet::Snapshot { players, bullets, enemies, score, health }),
+        delta(),
+        any::<u32>().prop_map(|tick| Packet::TickAck { tick }),
+        (any::<bool>(), field_packet()).prop_map(|(top, packet)| Packet::Spectated { top, packet: Box::new(packet) }),
+        Just(Packet::Ping),
+        Just(Packet::Pong),
+        any::<u32>().prop_map(|client_time| Packet::TimeRequest { client_time }),
+        (any::<u32>(), any::<u32>()).prop_map(|(client_time, server_time)| Packet::TimeReply { client_time, server_time }),
+    ]
+}
+
+fn encode_all(format: WireFormat, packets: &[Packet]) -> BytesMut {
+    let mut codec = PacketCodec::new(format);
+    let mut buf = BytesMut::new();
+    for packet in packets {
+        codec.encode(packet.clone(), &mut buf).unwrap();
+    }
+    buf
+}
+
+proptest! {
+    #[test]
+    fn round_trip(packet in packet()) {
+        for format in formats() {
+            let mut buf = encode_all(format, std::slice::from_ref(&packet));
+            let decoded = PacketCodec::new(format).decode(&mut buf).unwrap();
+            prop_assert_eq!(decoded.map(|p| p.unwrap()), Some(packet.clone()));
+            prop_assert!(buf.is_empty());
+        }
+    }
+
+    // feed the stream one byte at a time, like a slow non-blocking socket
+    #[test]
+    fn round_trip_partial_reads(packets in prop::collection::vec(packet(), 1..8)) {
+        for format in formats() {
+            let raw = encode_all(format, &packets);
+            let mut codec = PacketCodec::new(format);
+            let mut buf = BytesMut::new();
+            let mut decoded = Vec::new();
+            for byte in raw.iter() {
+                buf.extend_from_slice(&[*byte]);
+                while let Some(packet) = codec.decode(&mut buf).unwrap() {
+                    decoded.push(packet.unwrap());
+                }
+            }
+            prop_assert_eq!(&decoded, &packets);
+        }
+    }
+
+    #[test]
+    fn round_trip_blocking(packets in prop::collection::vec(packet(), 1..8)) {
+        for format in formats() {
+            let raw = encode_all(format, &packets);
+            let mut framed = BlockingFramed::new(Cursor::new(raw.to_vec()), PacketCodec::new(format));
+            let mut decoded = Vec::new();
+            while let Ok(Some(packet)) = framed.recv() {
+                decoded.push(packet.unwrap());
+            }
+            prop_assert_eq!(&decoded, &packets);
+        }
+    }
+
+    #[test]
+    fn round_trip_async(packets in prop::collection::vec(packet(), 1..8)) {
+        let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        for format in formats() {
+            let raw = encode_all(format, &packets);
+            let decoded: Vec<Packet> = rt.block_on(async {
+                FramedRead::new(&raw[..], PacketCodec::new(format))
+                    .map(|packet| packet.unwrap().unwrap())
+                    .collect()
+                    .await
+            });
+            prop_assert_eq!(&decoded, &packets);
+        }
+    }
+
+    #[test]
+    fn arbitrary_bytes_do_not_panic(raw in prop::collection::vec(any::<u8>(), 0..512)) {
+        for format in formats() {
+            let mut framed = BlockingFramed::new(Cursor::new(raw.clone()), PacketCodec::new(format));
+            while let Ok(Some(_)) = framed.recv() {}
+        }
+    }
+}
+
+#[test]
+fn malformed_frames_are_reported_and_skipped() {
+    let mut buf = BytesMut::new();
+    buf.extend_from_slice(&[0x04, 0x00, 0x05, 0x00, 0x00, 0x00, 0x01, 0xFF]); // PlayerEvent with an invalid event
+    buf.extend_from_slice(&[0x7F, 0x00, 0x02, 0xAB, 0xCD]); // unknown packet id
+    buf.extend_from_slice(&[0x05, 0x00, 0x04, 0x00, 0x00, 0x00, 0x01]); // PlayerPos without coordinates
+    buf.extend_from_slice(&[0x02, 0x00, 0x04, 0x00, 0x03, 0x01, 0xFF]); // PlayerJoin with a non utf-8 name
+    buf.extend_from_slice(&encode_all(WireFormat::Binary, &[Packet::GameWon]));
+
+    let mut codec = PacketCodec::new(WireFormat::Binary);
+    let mut next = || codec.decode(&mut buf).unwrap().unwrap();
+    assert!(matches!(next(), Err(ProtocolError::InvalidEvent(0xFF))));
+    assert!(matches!(next(), Err(ProtocolError::UnknownPacket(0x7F))));
+    assert!(matches!(next(), Err(ProtocolError::Truncated)));
+    assert!(matches!(next(), Err(ProtocolError::InvalidUtf8)));
+    assert_eq!(next().unwrap(), Packet::GameWon);
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn closed_stream_is_unexpected_eof() {
+    let mut framed = BlockingFramed::new(Cursor::new(Vec::new()), PacketCodec::new(WireFormat::Binary));
+    assert!(matches!(framed.recv(), Err(ProtocolError::UnexpectedEof)));
+}
@@ -0,0 +1,153 @@
+use std::time::Instant;
+
+use bytes::BytesMut;
+use proptest::prelude::*;
+
+use common::*;
+
+// reliable packets carry their index in a BulletDestroy, unreliable ones in a PlayerPos of one of two players
+fn packet(index: u32, reliable: bool) -> Packet {
+    if reliable {
+        Packet::BulletDestroy(format!("{:0width$}", index, width = BULLET_ID_LEN))
+    } else {
+        Packet::PlayerPos { pid: 1 + index % 2, x: index, y: 0, time: index }
+    }
+}
+
+// passes datagrams on the way a bad network would: some get lost, some come twice, some come late.
+// Once the fates run out nothing is lost anymore
+fn transmit(datagrams: Vec<BytesMut>, fates: &mut impl Iterator<Item = u8>) -> Vec<BytesMut> {
+    let (mut arrived, mut late) = (Vec::new(), Vec::new());
+    for datagram in datagrams {
+        match fates.next().unwrap_or(1) % 4 {
+            0 => (),
+            1 => arrived.push(datagram),
+            2 => {
+                arrived.push(datagram.clone());
+                late.push(datagram);
+            }
+            _ => late.push(datagram),
+        }
+    }
+    arrived.extend(late.into_iter().rev());
+    arrived
+}
+
+proptest! {
+    #[test]
+    fn reliable_packets_arrive_once_and_in_order(
+        kinds in prop::collection::vec(any::<bool>(), 1..64),
+        fates in prop::collection::vec(any::<u8>(), 0..256),
+    ) {
+        let codec = PacketCodec::new(WireFormat::Binary);
+        let (mut sender, mut receiver) = (Link::new(codec), Link::new(codec));
+        let mut fates = fates.into_iter();
+        let mut now = Instant::now();
+
+        let sent: Vec<Packet> = kinds.iter().enumerate().map(|(i, reliable)| packet(i as u32, *reliable)).collect();
+        let mut in_flight: Vec<BytesMut> = sent.iter().map(|p| sender.send(p.clone(), now).unwrap()).collect();
+        let mut received = Vec::new();
+        // every round takes at least one fate while something is unacked
+        for _ in 0..300 {
+            let mut acks = Vec::new();
+            for datagram in transmit(in_flight, &mut fates) {
+                acks.extend(receiver.receive(&datagram).unwrap());
+            }
+            while let Some(packet) = receiver.recv() {
+                received.push(packet.unwrap());
+            }
+            for ack in transmit(acks, &mut fates) {
+                prop_assert!(sender.receive(&ack).unwrap().is_none());
+            }
+            if sender.is_idle() {
+                break;
+            }
+            now += RESEND_AFTER;
+            in_flight = sender.resend(now);
+        }
+        prop_assert!(sender.is_idle());
+
+        let (reliable, unreliable): (Vec<Packet>, Vec<Packet>) = received.into_iter().partition(Packet::is_reliable);
+        let sent_reliable: Vec<Packet> = sent.iter().filter(|p| p.is_reliable()).cloned().collect();
+        prop_assert_eq!(reliable, sent_reliable);
+        for player in 1..=2 {
+            let times: Vec<u32> = unreliable.iter().filter_map(|p| match p {
+                Packet::PlayerPos { pid, time, .. } if *pid == player => Some(*time),
+                _ => None,
+            }).collect();
+            prop_assert!(times.windows(2).all(|pair| pair[0] < pair[1]), "outdated packet handed out: {:?}", times);
+        }
+    }
+
+    #[test]
+    fn arbitrary_datagrams_do_not_panic(raw in prop::collection::vec(any::<u8>(), 0..512)) {
+        let mut link = Link::new(PacketCodec::new(WireFormat::Binary));
+        let _ = link.receive(&raw);
+        while link.recv().is_some() {}
+    }
+}
+
+#[test]
+fn only_the_first_reliable_packet_opens_a_link() {
+    let mut link = Link::new(PacketCodec::new(WireFormat::Binary));
+    let now = Instant::now();
+    let position = link.send(packet(0, false), now).unwrap();
+    let first = link.send(packet(1, true), now).unwrap();
+    let second = link.send(packet(2, true), now).unwrap();
+    assert!(!Link::opens(&position));
+    assert!(Link::opens(&first));
+    assert!(!Link::opens(&second));
+}
+
+#[test]
+fn unreliable_packets_are_only_outdated_by_their_own_stream() {
+    let codec = PacketCodec::new(WireFormat::Binary);
+    let (mut sender, mut receiver) = (Link::new(codec), Link::new(codec));
+    let now = Instant::now();
+    let sent = [
+        Packet::PlayerPos { pid: 1, x: 0, y: 0, time: 0 },
+        Packet::PlayerPos { pid: 2, x: 0, y: 0, time: 0 },
+        Packet::Ping,
+        Packet::PlayerPos { pid: 1, x: 1, y: 0, time: 1 },
+    ];
+    let datagrams: Vec<BytesMut> = sent.iter().map(|p| sender.send(p.clone(), now).unwrap()).collect();
+    for i in [3, 2, 1, 0] {
+        receiver.receive(&datagrams[i]).unwrap();
+    }
+    let received: Vec<Packet> = std::iter::from_fn(|| receiver.recv()).map(Result::unwrap).collect();
+    assert_eq!(received, [sent[3].clone(), sent[2].clone(), sent[1].clone()]);
+}
+
+#[test]
+fn a_link_is_made_once_the_cookie_comes_back() {
+    let codec = PacketCodec::new(WireFormat::Binary);
+    let mut client = Link::new(codec);
+    let first = client.send(packet(1, true), Instant::now()).unwrap();
+    assert!(Link::opens(&first));
+    assert_eq!(Link::echoed_cookie(&first), None);
+
+    let opening = client.receive(&Link::cookie(42)).unwrap().unwrap();
+    assert!(!Link::opens(&opening));
+    assert_eq!(Link::echoed_cookie(&opening), Some(42));
+    assert!(client.receive(&Link::cookie(7)).unwrap().is_none()); // only the first one counts
+    // the cookie stays with the first packet until it is acked
+    assert_eq!(Link::echoed_cookie(&client.resend(Instant::now() + RESEND_AFTER)[0]), Some(42));
+
+    let mut server = Link::new(codec);
+    let ack = server.receive(&opening).unwrap().unwrap();
+    assert_eq!(server.recv().unwrap().unwrap(), packet(1, true));
+    client.receive(&ack).unwrap();
+    assert!(client.is_idle());
+}
+
+#[test]
+fn malformed_datagrams_are_reported() {
+    let mut link = Link::new(PacketCodec::new(WireFormat::Binary));
+    assert!(matches!(link.receive(&[0x01, 0x00]), Err(ProtocolError::Truncated)));
+    assert!(matches!(link.receive(&[0x07, 0, 0, 0, 1, 0, 0, 0, 0]), Err(ProtocolError::InvalidDatagram(0x07))));
+    assert!(matches!(link.receive(&[0x03, 0, 0, 0, 0, 0, 0, 0, 0, 0x2A]), Err(ProtocolError::Truncated)));
+    // a reliable datagram with a broken frame is still acked, its error takes the packet's turn
+    assert!(link.receive(&[0x01, 0, 0, 0, 1, 0, 0, 0, 0, 0x7F, 0x00, 0x00]).unwrap().is_some());
+    assert!(matches!(link.recv(), Some(Err(ProtocolError::UnknownPacket(0x7F)))));
+    assert!(link.recv().is_none());
+}
@@ -1,20 +1,19 @@
-use common::BULLET_UPDATE_MOVEMENT;
-
 #[derive(Clone, Debug)]
 pub struct Bullet {
     x: u32,
     y: u32,
     radius: u32,
     max_y: u32,
+    speed: u32, // in pixels per update
 }
 
 impl Bullet {
-    pub fn new(x: u32, y: u32, max_y: u32) -> Self {
-        Bullet { x, y, radius: 10, max_y }
+    pub fn new(x: u32, y: u32, max_y: u32, speed: u32) -> Self {
+        Bullet { x, y, radius: 10, max_y, speed }
     }
     pub fn fly(&mut self) -> bool {
-        if self.y >= BULLET_UPDATE_MOVEMENT { 
-            self.y -= BULLET_UPDATE_MOVEMENT;
+        if self.y >= self.speed { 
+            self.y -= self.speed;
         } else {
             self.y = 0;
         }
@@ -22,7 +21,7 @@ impl Bullet {
     }
     pub fn fall(&mut self) -> bool {
         if self.y < self.max_y { 
-            self.y += BULLET_UPDATE_MOVEMENT;
+            self.y += self.speed;
         } else {
             self.y = self.max_y;
         }
@@ -0,0 +1,16 @@
+use std::time::Instant;
+
+// the server's time, in milliseconds since it started; positions are stamped with it
+// so clients can place them on a timeline whatever the network did to them
+#[derive(Clone, Copy, Debug)]
+pub struct Clock(Instant);
+
+impl Clock {
+    pub fn new() -> Clock {
+        Clock(Instant::now())
+    }
+
+    pub fn now(&self) -> u32 {
+        self.0.elapsed().as_millis() as u32 // wraps after 49 days
+    }
+}
@@ -0,0 +1,229 @@
+use std::{net::{IpAddr, Ipv4Addr}, path::PathBuf, str::FromStr, time::Duration};
+
+use serde::Deserialize;
+use structopt::StructOpt;
+
+use common::*;
+
+use crate::player::{Heartbeat, Movement};
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "goshooter_server", about = "GoShooter multiplayer server")]
+struct Opt {
+    /// TOML config file, command-line flags override its values
+    #[structopt(short, long, parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Address to listen on, use :: for IPv6
+    #[structopt(short, long)]
+    bind: Option<IpAddr>,
+    /// Port to listen on
+    #[structopt(short, long)]
+    port: Option<u16>,
+    /// Arena width in pixels
+    #[structopt(long)]
+    width: Option<u32>,
+    /// Arena height in pixels
+    #[structopt(long)]
+    height: Option<u32>,
+    /// Players per match, split evenly between the two teams
+    #[structopt(long)]
+    players: Option<u8>,
+    /// Score a team needs to win
+    #[structopt(long)]
+    win_score: Option<u32>,
+    /// Minimum time between two shots of a player, in ms
+    #[structopt(long)]
+    fire_interval: Option<u32>,
+    /// Pixels a bullet moves per bullet update
+    #[structopt(long)]
+    bullet_speed: Option<u32>,
+    /// Who moves the players: clients send positions, or inputs the server simulates
+    #[structopt(long)]
+    control: Option<Control>,
+    /// Fastest a player may move, in pixels per second
+    #[structopt(long)]
+    max_speed: Option<u32>,
+    /// Seconds counted down once all players are ready
+    #[structopt(long)]
+    countdown: Option<u8>,
+    /// Keep the players of a finished match for another one
+    #[structopt(long)]
+    rematch: bool,
+    /// What happens when a team has no players left: forfeit, pause or play
+    #[structopt(long)]
+    empty_team: Option<EmptyTeam>,
+    /// Time between two pings to each client, in ms
+    #[structopt(long)]
+    heartbeat_interval: Option<u32>,
+    /// Time without hearing from a client before it is dropped, in ms
+    #[structopt(long)]
+    heartbeat_timeout: Option<u32>,
+    /// Time a player whose connection dropped has to come back, in ms, 0 to drop it right away
+    #[structopt(long)]
+    reconnect_grace: Option<u32>,
+    /// Packet encoding, binary or json
+    #[structopt(long)]
+    wire_format: Option<WireFormat>,
+    /// What clients connect over: tcp, or udp where only events are resent when lost
+    #[structopt(long)]
+    transport: Option<Transport>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: IpAddr,
+    pub port: u16,
+    pub width: u32,
+    pub height: u32,
+    pub players: u8,
+    pub win_score: u32,
+    pub fire_interval: u32, // in ms
+    pub bullet_speed: u32, // in pixels
+    pub control: Control,
+    pub max_speed: u32, // in pixels per second
+    pub countdown: u8, // in seconds
+    pub rematch: bool,
+    pub empty_team: EmptyTeam,
+    pub heartbeat_interval: u32, // in ms
+    pub heartbeat_timeout: u32, // in ms
+    pub reconnect_grace: u32, // in ms
+    pub wire_format: WireFormat,
+    pub transport: Transport,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let rules = GameRules::default();
+        Config {
+            bind: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 6773,
+            width: 600,
+            height: 600,
+            players: 2,
+            win_score: rules.win_score,
+            fire_interval: rules.fire_interval,
+            bullet_speed: rules.bullet_speed,
+            control: Control::Positions,
+            max_speed: 1500, // a little above what the client allows with shift held
+            countdown: 3,
+            rematch: false,
+            empty_team: EmptyTeam::Forfeit,
+            heartbeat_interval: 1000,
+            heartbeat_timeout: 5000,
+            reconnect_grace: 15000,
+            wire_format: WireFormat::Binary,
+            transport: Transport::Tcp,
+        }
+    }
+}
+
+impl Config {
+    // defaults, overridden by the config file, overridden by command-line flags
+    pub fn load() -> std::result::Result<Config, String> {
+        let opt = Opt::from_args();
+        let mut config = match &opt.config {
+            Some(path) => {
+                let raw = std::fs::read_to_string(path)
+                    .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+                toml::from_str(&raw).map_err(|e| format!("invalid config {}: {}", path.display(), e))?
+            }
+            None => Config::default(),
+        };
+
+        if let Some(bind) = opt.bind { config.bind = bind; }
+        if let Some(port) = opt.port { config.port = port; }
+        if let Some(width) = opt.width { config.width = width; }
+        if let Some(height) = opt.height { config.height = height; }
+        if let Some(players) = opt.players { config.players = players; }
+        if let Some(win_score) = opt.win_score { config.win_score = win_score; }
+        if let Some(fire_interval) = opt.fire_interval { config.fire_interval = fire_interval; }
+        if let Some(bullet_speed) = opt.bullet_speed { config.bullet_speed = bullet_speed; }
+        if let Some(control) = opt.control { config.control = control; }
+        if let Some(max_speed) = opt.max_speed { config.max_speed = max_speed; }
+        if let Some(countdown) = opt.countdown { config.countdown = countdown; }
+        if opt.rematch { config.rematch = true; }
+        if let Some(empty_team) = opt.empty_team { config.empty_team = empty_team; }
+        if let Some(interval) = opt.heartbeat_interval { config.heartbeat_interval = interval; }
+        if let Some(timeout) = opt.heartbeat_timeout { config.heartbeat_timeout = timeout; }
+        if let Some(grace) = opt.reconnect_grace { config.reconnect_grace = grace; }
+        if let Some(wire_format) = opt.wire_format { config.wire_format = wire_format; }
+        if let Some(transport) = opt.transport { config.transport = transport; }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn rules(&self) -> GameRules {
+        GameRules {
+            fire_interval: self.fire_interval,
+            win_score: self.win_score,
+            bullet_speed: self.bullet_speed,
+            control: self.control,
+            ..GameRules::default()
+        }
+    }
+
+    pub fn heartbeat(&self) -> Heartbeat {
+        Heartbeat {
+            interval: Duration::from_millis(self.heartbeat_interval as u64),
+            timeout: Duration::from_millis(self.heartbeat_timeout as u64),
+        }
+    }
+
+    pub fn movement(&self) -> Movement {
+        Movement {
+            max_x: self.width,
+            max_y: self.height,
+            max_speed: self.max_speed,
+            control: self.control,
+        }
+    }
+
+    pub fn reconnect_grace(&self) -> Duration {
+        Duration::from_millis(self.reconnect_grace as u64)
+    }
+
+    fn validate(&self) -> std::result::Result<(), String> {
+        if self.players < 2 || !self.players.is_multiple_of(2) {
+            return Err(format!("players must be an even number of at least 2, got {}", self.players));
+        }
+        if self.width == 0 || self.height < 2 {
+            return Err(String::from("arena is too small"));
+        }
+        if self.win_score == 0 {
+            return Err(String::from("win_score must be at least 1"));
+        }
+        if self.bullet_speed == 0 {
+            return Err(String::from("bullet_speed must be at least 1"));
+        }
+        if self.max_speed == 0 {
+            return Err(String::from("max_speed must be at least 1"));
+        }
+        if self.heartbeat_interval == 0 || self.heartbeat_timeout <= self.heartbeat_interval {
+            return Err(String::from("heartbeat_timeout must be longer than a non-zero heartbeat_interval"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EmptyTeam {
+    Forfeit, // the other team wins
+    Pause, // the match waits while no player of the team is connected, forfeited once none can come back
+    Play, // the match goes on, the other team will usually win on points
+}
+
+impl FromStr for EmptyTeam {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "forfeit" => Ok(Self::Forfeit),
+            "pause" => Ok(Self::Pause),
+            "play" => Ok(Self::Play),
+            _ => Err(format!("unknown empty team policy '{}'", s)),
+        }
+    }
+}
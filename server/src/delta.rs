@@ -0,0 +1,223 @@
+use std::collections::{HashMap, VecDeque};
+
+use common::*;
+
+const MAX_UNACKED: usize = 32; // ticks kept for a client that stopped acking, it gets everything again after that
+const MAX_DRIFT: u32 = 10; // pixels an object may be from where the client puts it before it is sent again, a bullet's radius
+
+// bullets and enemies by id, at some tick
+type Objects = (HashMap<String, (u32, u32)>, HashMap<String, (u32, u32)>);
+
+// what one connection was sent at each tick since the last tick its client acked,
+// so every Delta only carries what the client cannot work out from a state it is known to have
+#[derive(Default)]
+pub struct Baselines {
+    rules: GameRules, // how far objects fly between two ticks
+    sent: VecDeque<(u32, u32, Objects)>, // tick, its time, and the objects as the client has them
+    acked: u32, // 0 until the client applied a Delta
+}
+
+impl Baselines {
+    // for a new game, with the rules it is played by
+    pub fn start(&mut self, rules: &GameRules) {
+        self.rules = rules.clone();
+        self.clear();
+    }
+
+    // for a new connection, the client starts over
+    pub fn clear(&mut self) {
+        self.sent.clear();
+        self.acked = 0;
+    }
+
+    pub fn ack(&mut self, tick: u32) {
+        if tick > self.acked && self.sent.iter().any(|(sent, _, _)| *sent == tick) {
+            self.acked = tick;
+            self.sent.retain(|(sent, _, _)| *sent >= tick); // never built on again
+        }
+    }
+
+    // turns all bullets and enemies at a tick into a Delta against the acked tick
+    pub fn compress(&mut self, tick: u32, time: u32, bullets: Vec<BulletSnapshot>, enemies: Vec<BulletSnapshot>) -> Packet {
+        let (packet, objects) = match self.sent.iter().find(|(sent, _, _)| *sent == self.acked) {
+            Some((baseline, baseline_time, old)) => {
+                // where the client puts what it had at the baseline, without being told
+                let predicted = fly(old, self.rules.bullet_travel(time.wrapping_sub(*baseline_time)));
+                let mut objects = Objects::default();
+                let bullets = changed(bullets, &predicted.0, &mut objects.0);
+                let enemies = changed(enemies, &predicted.1, &mut objects.1);
+                let removed = old.0.keys().filter(|id| !objects.0.contains_key(*id))
+                    .chain(old.1.keys().filter(|id| !objects.1.contains_key(*id)))
+                    .cloned()
+                    .collect();
+                (Packet::Delta { tick, time, baseline: *baseline, bullets, enemies, removed }, objects)
+            }
+            None => {
+                let objects = (by_id(&bullets), by_id(&enemies));
+                (Packet::Delta { tick, time, baseline: 0, bullets, enemies, removed: Vec::new() }, objects)
+            }
+        };
+        self.sent.push_back((tick, time, objects));
+        if self.sent.len() > MAX_UNACKED {
+            self.sent.pop_front();
+        }
+        packet
+    }
+}
+
+// bullets fly up and enemies fall down, the client does the same with what it was not sent
+fn fly((bullets, enemies): &Objects, travel: u32) -> Objects {
+    (
+        bullets.iter().map(|(id, (x, y))| (id.clone(), (*x, y.saturating_sub(travel)))).collect(),
+        enemies.iter().map(|(id, (x, y))| (id.clone(), (*x, y.saturating_add(travel)))).collect(),
+    )
+}
+
+fn by_id(objects: &[BulletSnapshot]) -> HashMap<String, (u32, u32)> {
+    objects.iter().map(|(id, x, y)| (id.clone(), (*x, *y))).collect()
+}
+
+// the objects too far from where the client puts them, all of them end up where the client has them
+fn changed(objects: Vec<BulletSnapshot>, predicted: &HashMap<String, (u32, u32)>, client: &mut HashMap<String, (u32, u32)>) -> Vec<BulletSnapshot> {
+    objects.into_iter().filter(|(id, x, y)| {
+        match predicted.get(id) {
+            Some(&(px, py)) if px == *x && py.abs_diff(*y) <= MAX_DRIFT => {
+                client.insert(id.clone(), (px, py));
+                false
+            }
+            _ => {
+                client.insert(id.clone(), (*x, *y));
+                true
+            }
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // what a client makes of the Deltas it got, by tick
+    #[derive(Default)]
+    struct Client(HashMap<u32, (u32, Objects)>);
+
+    impl Client {
+        fn apply(&mut self, delta: Packet) -> &Objects {
+            let (tick, time, baseline, bullets, enemies, removed) = match delta {
+                Packet::Delta { tick, time, baseline, bullets, enemies, removed } => (tick, time, baseline, bullets, enemies, removed),
+                _ => unreachable!(),
+            };
+            let mut objects = match baseline {
+                0 => Objects::default(),
+                _ => {
+                    let (baseline_time, objects) = &self.0[&baseline];
+                    fly(objects, GameRules::default().bullet_travel(time - baseline_time))
+                }
+            };
+            for id in &removed {
+                objects.0.remove(id);
+                objects.1.remove(id);
+            }
+            objects.0.extend(by_id(&bullets));
+            objects.1.extend(by_id(&enemies));
+            &self.0.entry(tick).or_insert((time, objects)).1
+        }
+    }
+
+    fn snapshots(objects: &[(&str, u32)]) -> Vec<BulletSnapshot> {
+        objects.iter().map(|(id, y)| (id.to_string(), 5, *y)).collect()
+    }
+
+    fn close_to(client: &HashMap<String, (u32, u32)>, server: &[BulletSnapshot]) -> bool {
+        client.len() == server.len() && server.iter().all(|(id, x, y)| {
+            matches!(client.get(id), Some((cx, cy)) if cx == x && cy.abs_diff(*y) <= MAX_DRIFT)
+        })
+    }
+
+    #[test]
+    fn first_delta_carries_everything() {
+        let mut baselines = Baselines::default();
+        match baselines.compress(1, 10, snapshots(&[("a", 1)]), snapshots(&[("e", 2)])) {
+            Packet::Delta { baseline: 0, bullets, enemies, removed, .. } => {
+                assert_eq!(bullets, snapshots(&[("a", 1)]));
+                assert_eq!(enemies, snapshots(&[("e", 2)]));
+                assert!(removed.is_empty());
+            }
+            packet => panic!("unexpected {:?}", packet),
+        }
+    }
+
+    #[test]
+    fn moving_objects_are_not_sent_again() {
+        let mut baselines = Baselines::default();
+        baselines.start(&GameRules::default()); // 2 pixels every 8 ms
+        for tick in 1..=10 {
+            let time = tick * 50;
+            let moved = GameRules::default().bullet_travel(time);
+            let delta = baselines.compress(tick, time, snapshots(&[("a", 500 - moved)]), snapshots(&[("e", moved)]));
+            match delta {
+                Packet::Delta { baseline: 0, .. } => assert_eq!(tick, 1),
+                Packet::Delta { bullets, enemies, removed, .. } => assert!(bullets.is_empty() && enemies.is_empty() && removed.is_empty()),
+                packet => panic!("unexpected {:?}", packet),
+            }
+            baselines.ack(tick);
+        }
+        // one that did not fly on as expected is
+        match baselines.compress(11, 550, snapshots(&[("a", 100)]), snapshots(&[("e", 125)])) {
+            Packet::Delta { bullets, enemies, .. } => {
+                assert_eq!(bullets, snapshots(&[("a", 100)]));
+                assert!(enemies.is_empty());
+            }
+            packet => panic!("unexpected {:?}", packet),
+        }
+    }
+
+    #[test]
+    fn removals_reach_a_client_across_unacked_ticks() {
+        let (mut baselines, mut client) = (Baselines::default(), Client::default());
+        let states = [
+            snapshots(&[("a", 100), ("b", 300)]),
+            snapshots(&[("a", 88), ("b", 200)]), // b was moved
+            snapshots(&[("a", 76)]), // b gone, and this Delta is lost
+            snapshots(&[("a", 64), ("c", 400)]),
+            snapshots(&[("c", 388)]),
+        ];
+        for (i, bullets) in states.iter().enumerate() {
+            let tick = i as u32 + 1;
+            let delta = baselines.compress(tick, tick * 50, bullets.clone(), Vec::new());
+            if tick == 3 {
+                continue;
+            }
+            assert!(close_to(&client.apply(delta).0, bullets), "tick {}", tick);
+            if tick <= 2 {
+                baselines.ack(tick); // later acks are lost
+            }
+        }
+    }
+
+    #[test]
+    fn acks_move_the_baseline_forward_only() {
+        let mut baselines = Baselines::default();
+        baselines.compress(1, 10, snapshots(&[("a", 1)]), Vec::new());
+        baselines.compress(2, 20, snapshots(&[("a", 2)]), Vec::new());
+        baselines.ack(2);
+        baselines.ack(1); // overtaken
+        baselines.ack(7); // never sent
+        match baselines.compress(3, 20, snapshots(&[("a", 2)]), Vec::new()) {
+            Packet::Delta { baseline: 2, bullets, removed, .. } => assert!(bullets.is_empty() && removed.is_empty()),
+            packet => panic!("unexpected {:?}", packet),
+        }
+    }
+
+    #[test]
+    fn a_client_that_stops_acking_gets_everything_again() {
+        let mut baselines = Baselines::default();
+        baselines.compress(1, 10, snapshots(&[("a", 1)]), Vec::new());
+        baselines.ack(1);
+        for tick in 2..MAX_UNACKED as u32 + 2 {
+            assert!(matches!(baselines.compress(tick, tick * 10, snapshots(&[("a", 1)]), Vec::new()), Packet::Delta { baseline: 1, .. }));
+        }
+        let tick = MAX_UNACKED as u32 + 2;
+        assert!(matches!(baselines.compress(tick, tick * 10, snapshots(&[("a", 1)]), Vec::new()), Packet::Delta { baseline: 0, .. }));
+    }
+}
@@ -1,12 +1,23 @@
+use config::Config;
 use server::Server;
 
+mod clock;
+mod config;
+mod delta;
 mod team;
 mod server;
+mod room;
 mod player;
+mod spectator;
 mod bullet;
+mod transport;
 
 #[tokio::main]
 async fn main() {
-    let mut server = Server::new(600, 600, 2, 6773).await.unwrap();
-    server.start().await.unwrap();
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    let mut server = Server::new(&config).await.unwrap();
+    server.start().await;
 }
@@ -1,97 +1,427 @@
-use std::{time::{self, Duration}};
-
-use tokio::{io::{AsyncWriteExt, BufReader}, net::TcpListener, sync::{broadcast}};
+use std::{
+    collections::VecDeque,
+    sync::{atomic::{AtomicBool, Ordering}, Arc},
+    time::{self, Duration},
+};
 
+use futures::{SinkExt, StreamExt};
+use nanoid::nanoid;
+use tokio::{
+    sync::{broadcast, mpsc},
+    task::JoinHandle,
+    time::{self as ticker, timeout},
+};
 use common::*;
 
-use crate::{bullet::Bullet};
+use crate::{bullet::Bullet, clock::Clock, delta::Baselines, transport::{PacketSink, PacketStream}};
+
+#[derive(Clone, Copy, Debug)]
+pub struct Heartbeat {
+    pub interval: Duration, // between two pings
+    pub timeout: Duration, // without any packet from the client before it is dropped
+}
+
+// where and how fast players may move
+#[derive(Clone, Copy, Debug)]
+pub struct Movement {
+    pub max_x: u32,
+    pub max_y: u32,
+    pub max_speed: u32, // in pixels per second
+    pub control: Control,
+}
+
+impl Movement {
+    // where players start a match
+    fn start(&self) -> (u32, u32) {
+        (self.max_x/2, self.max_y.saturating_sub(START_HEIGHT))
+    }
+
+    // where a position sent by a client puts its player: inside the arena, and players only move along x
+    fn place(&self, x: u32) -> (u32, u32) {
+        (x.min(self.max_x), self.start().1)
+    }
+
+    // whether a player may move between two x positions, paid for from its budget in pixels.
+    // The budget refills at max_speed over the time since the last check, up to MOVE_BURST worth,
+    // so positions bunched up by the network pass but standing still does not save up for a jump
+    fn allows(&self, budget: &mut f64, from: u32, to: u32, elapsed: Duration) -> bool {
+        let refill = self.max_speed as f64 * elapsed.as_secs_f64();
+        *budget = (*budget + refill).min(self.max_speed as f64 * MOVE_BURST.as_secs_f64());
+        let distance = (from as f64 - to as f64).abs();
+        if distance > *budget {
+            return false;
+        }
+        *budget -= distance;
+        true
+    }
+}
+
+const TOKEN_LEN: usize = 16;
+const START_HEIGHT: u32 = 100; // players start this far above the bottom, where clients draw them
+const MOVE_BURST: Duration = Duration::from_millis(150); // movement a player may save up, see Movement::allows
+const MAX_QUEUED_INPUTS: usize = 8; // inputs beyond this are dropped, sending faster does not move faster
+
+// a reconnecting client and its subscription to the team, taken before the missed state was sent
+type Resume = (Joining, broadcast::Receiver<Packet>);
+
+// a client that completed the join handshake but is not in a room yet
+pub struct Joining {
+    pub pid: PlayerId,
+    pub name: String,
+    pub room: String, // requested room, empty for any
+    pub token: String, // lets the client resume this player after losing the connection
+    pub resume: String, // token of the player the client wants back, empty for a new player
+    pub spectate: bool, // watches both teams instead of playing
+    stream_r: PacketStream,
+    stream_w: PacketSink,
+}
+
+impl Joining {
+    pub async fn handshake(mut stream_r: PacketStream, mut stream_w: PacketSink, pid: PlayerId, heartbeat: Heartbeat) -> Result<Joining> {
+        let join = timeout(heartbeat.timeout, stream_r.next()).await
+            .map_err(|_| ProtocolError::HandshakeFailed(String::from("timed out waiting for PlayerJoin")))?;
+        let (name, room, resume, spectate) = match join {
+            Some(Ok(Ok(Packet::PlayerJoin { version, name, room, token, spectate }))) if version == PROTOCOL_VERSION => {
+                (name, room, token, spectate)
+            }
+            Some(Ok(Ok(Packet::PlayerJoin { version, .. }))) => {
+                let reason = format!("server speaks protocol version {}, client {}", PROTOCOL_VERSION, version);
+                stream_w.send(Packet::Rejected { reason }).await?;
+                return Err(ProtocolError::VersionMismatch { local: PROTOCOL_VERSION, remote: version });
+            }
+            Some(Ok(Ok(packet))) => {
+                return Err(ProtocolError::HandshakeFailed(format!("expected PlayerJoin, got {:?}", packet)));
+            }
+            Some(Ok(Err(e))) | Some(Err(e)) => return Err(e),
+            None => return Err(ProtocolError::UnexpectedEof),
+        };
+        let token = nanoid!(TOKEN_LEN);
+        let mut joining = Joining { pid, name, room, token, resume, spectate, stream_r, stream_w };
+        if joining.name.trim().is_empty() {
+            let reason = String::from("player name is empty");
+            joining.reject(&reason).await?;
+            return Err(ProtocolError::HandshakeFailed(reason));
+        }
+        Ok(joining)
+    }
+
+    pub async fn send(&mut self, packet: Packet) -> Result<()> {
+        self.stream_w.send(packet).await
+    }
+
+    pub async fn reject(&mut self, reason: &str) -> Result<()> {
+        self.send(Packet::Rejected { reason: reason.to_string() }).await
+    }
+
+    pub fn into_streams(self) -> (PacketStream, PacketSink) {
+        (self.stream_r, self.stream_w)
+    }
+}
 
 pub struct Player {
+    name: String,
+    token: String,
     x: u32,
     y: u32,
-    _max_x: u32,
-    max_y: u32,
+    movement: Movement,
+    inputs: VecDeque<(u32, Input)>, // not applied yet, with Control::Inputs
+    input_seq: u32, // of the last queued input
+    input: Input, // the last applied input
+    moving: bool, // moved by the last applied input
+    ready: bool, // in the lobby
     last_fired: time::Instant,
-    last_updated: time::Instant,
+    resume: mpsc::Sender<Resume>,
+    direct: mpsc::Sender<Packet>, // packets for this player only
+    connected: Arc<AtomicBool>, // cleared by the task while the player may still come back
+    task: JoinHandle<()>, // forwards packets between the socket and the team
 }
 
 impl Player {
     pub async fn new(
-                    max_x: u32, 
-                    max_y: u32, 
-                    listener: &TcpListener, 
+                    movement: Movement,
+                    joining: Joining,
+                    room: &str,
+                    heartbeat: Heartbeat,
+                    reconnect_grace: Duration,
+                    clock: Clock,
                     sender: broadcast::Sender<Packet>,
-                    mut reciever: broadcast::Receiver<Packet>
-                ) -> Result<(String, Player)> {
-        let (stream, _) = listener.accept().await.unwrap();
-        let (stream_r, mut stream_w) = stream.into_split();
-        let mut stream_r = BufReader::new(stream_r);
-
-        let id = if let Ok(Some(Packet::PlayerJoin(id))) = Packet::async_read_from(&mut stream_r).await {
-            id
-        } else {
-            return Err(String::from("Player id not recieved").into());
-        };
-        
-        let pid = id.clone();
-        tokio::spawn(async move {
+                ) -> Result<Player> {
+        let Joining { pid, name, token, mut stream_r, mut stream_w, .. } = joining;
+        stream_w.send(Packet::JoinAccepted { pid, room: room.to_string(), token: token.clone() }).await?;
+        println!("player {} joined room {} as {}", name, room, pid);
+
+        let mut reciever = sender.subscribe();
+        let (resume_send, mut resume_recv) = mpsc::channel::<Resume>(1);
+        let (direct_send, mut direct_recv) = mpsc::channel::<Packet>(8);
+        let connected_flag = Arc::new(AtomicBool::new(true));
+        let flag = connected_flag.clone();
+        let task = tokio::spawn(async move {
+            let mut heartbeat_ticker = ticker::interval(heartbeat.interval);
+            let mut last_heard = time::Instant::now();
+            let mut connected = true;
+            // the last position we accepted, moves are checked against it
+            let mut position = movement.start();
+            let (mut budget, mut checked_at) = (0.0, time::Instant::now());
+            let mut baselines = Baselines::default();
+            let grace = ticker::sleep(reconnect_grace);
+            tokio::pin!(grace);
             // Packet handling 
             loop {
-                tokio::select! {
-                    Ok(packet) = reciever.recv() => {
+                let lost = tokio::select! {
+                    Some((joining, new_reciever)) = resume_recv.recv() => { // replaces the old connection, if still open
+                        stream_r = joining.stream_r;
+                        stream_w = joining.stream_w;
+                        reciever = new_reciever;
+                        baselines.clear();
+                        connected = true;
+                        flag.store(true, Ordering::Relaxed);
+                        last_heard = time::Instant::now();
+                        println!("player {} is back", pid);
+                        None
+                    }
+                    _ = &mut grace, if !connected => {
+                        println!("player {} did not come back", pid);
+                        break;
+                    }
+                    Ok(packet) = reciever.recv(), if connected => {
                         match packet {
-                            Packet::PlayerEvent { .. } => (),
-                            Packet::GameWon | Packet::GameLost => {
-                                stream_w.write(&packet.parse()).await.unwrap();
-                                println!("shutting down player loop");
-                                return;
+                            Packet::PlayerEvent { .. } => None,
+                            Packet::PlayerInput { .. } => None,
+                            Packet::PlayerPos { pid: moved, .. } if moved == pid => None, // corrections are replies or InputAck
+                            Packet::Delta { tick, time, bullets, enemies, .. } => {
+                                let delta = baselines.compress(tick, time, bullets, enemies);
+                                stream_w.send(delta).await.err().map(|e| e.to_string())
+                            }
+                            packet => {
+                                if let Packet::GameInfo { rules, .. } = &packet { // everyone goes back to the start
+                                    position = movement.start();
+                                    (budget, checked_at) = (0.0, time::Instant::now());
+                                    baselines.start(rules);
+                                }
+                                stream_w.send(packet).await.err().map(|e| e.to_string())
                             }
-                            _ =>  { stream_w.write(&packet.parse()).await.unwrap(); },
                         }
                     }
-                    Ok(Some(packet)) = Packet::async_read_from(&mut stream_r) => {
+                    Some(packet) = direct_recv.recv(), if connected => stream_w.send(packet).await.err().map(|e| e.to_string()),
+                    _ = heartbeat_ticker.tick(), if connected => {
+                        if last_heard.elapsed() > heartbeat.timeout {
+                            Some(String::from("timed out"))
+                        } else {
+                            stream_w.send(Packet::Ping).await.err().map(|e| e.to_string())
+                        }
+                    }
+                    packet = stream_r.next(), if connected => {
                         match packet {
-                            Packet::PlayerEvent{ event, .. } => match event {
-                                PlayerEvent::Fire => {
-                                    sender.send(Packet::PlayerEvent{pid: pid.clone(), event}).unwrap();
-                                },
-                                PlayerEvent::Exit => {
-                                    sender.send(Packet::PlayerDestroy(pid.to_string())).unwrap();
-                                },
-                            },
-                            Packet::PlayerPos { pid, x, y, } => {
-                                sender.send(Packet::PlayerPos { pid: pid.to_string(), x, y }).unwrap();
-                            },
-                            _ => (),
+                            Some(Ok(packet)) => {
+                                last_heard = time::Instant::now();
+                                let mut reply = None;
+                                match packet {
+                                    Ok(Packet::PlayerEvent{ event, .. }) => match event {
+                                        PlayerEvent::Fire if movement.control == Control::Inputs => (), // see Input::fire
+                                        PlayerEvent::Fire | PlayerEvent::Ready | PlayerEvent::Resync => {
+                                            sender.send(Packet::PlayerEvent{pid, event}).unwrap();
+                                        },
+                                        PlayerEvent::Exit => {
+                                            sender.send(Packet::PlayerDestroy(pid)).unwrap();
+                                            println!("player {} left", pid);
+                                            return;
+                                        },
+                                    },
+                                    Ok(Packet::PlayerInput { seq, input, .. }) if movement.control == Control::Inputs => {
+                                        sender.send(Packet::PlayerInput { pid, seq, input }).unwrap();
+                                    },
+                                    Ok(Packet::TickAck { tick }) => baselines.ack(tick),
+                                    Ok(Packet::TimeRequest { client_time }) => {
+                                        reply = Some(Packet::TimeReply { client_time, server_time: clock.now() });
+                                    },
+                                    Ok(Packet::PlayerPos { x, .. }) if movement.control == Control::Positions => { // only its own player
+                                        let to = movement.place(x);
+                                        let allowed = movement.allows(&mut budget, position.0, to.0, checked_at.elapsed());
+                                        checked_at = time::Instant::now();
+                                        if allowed {
+                                            position = to;
+                                            sender.send(Packet::PlayerPos { pid, x: to.0, y: to.1, time: clock.now() }).unwrap();
+                                        } else { // put the client back where we think it is
+                                            println!("player {} moved too fast", pid);
+                                            reply = Some(Packet::PlayerPos { pid, x: position.0, y: position.1, time: clock.now() });
+                                        }
+                                    },
+                                    Ok(_) | Err(ProtocolError::UnknownPacket(_)) => (), // including Pong
+                                    Err(e) => println!("invalid packet from player {}: {}", pid, e),
+                                }
+                                match reply {
+                                    Some(packet) => stream_w.send(packet).await.err().map(|e| e.to_string()),
+                                    None => None,
+                                }
+                            }
+                            Some(Err(e)) => Some(e.to_string()),
+                            None => Some(String::from("disconnected")),
                         }
                     }
+                };
+                if let Some(reason) = lost {
+                    println!("connection to player {} lost: {}", pid, reason);
+                    if reconnect_grace.is_zero() {
+                        break;
+                    }
+                    connected = false;
+                    flag.store(false, Ordering::Relaxed);
+                    grace.as_mut().reset(ticker::Instant::now() + reconnect_grace);
                 }
             }
+            // gone without saying goodbye
+            sender.send(Packet::PlayerDestroy(pid)).unwrap();
         });
 
-        Ok((id, Player { 
-            x: max_x/2, 
-            _max_x: max_x,
-            y: max_y-2,
-            max_y, 
+        let (x, y) = movement.start();
+        Ok(Player { 
+            name,
+            token,
+            x,
+            y,
+            movement,
+            inputs: VecDeque::new(),
+            input_seq: 0,
+            input: Input::default(),
+            moving: false,
+            ready: false,
             last_fired: time::Instant::now(),
-            last_updated: time::Instant::now(),
-        }))
+            resume: resume_send,
+            direct: direct_send,
+            connected: connected_flag,
+            task,
+        })
+    }
+    // hands the player a new connection; the state it missed must have been sent already
+    pub fn resume(&self, joining: Joining, reciever: broadcast::Receiver<Packet>) {
+        // only fails while another connection is being handed over, or once the player is gone
+        if self.resume.try_send((joining, reciever)).is_err() {
+            println!("player {} could not be resumed", self.name);
+        }
+    }
+    // sends a packet to this player alone, dropped if the connection cannot keep up
+    pub fn send(&self, packet: Packet) {
+        let _ = self.direct.try_send(packet);
+    }
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+    // the connection is gone for good
+    pub fn has_left(&self) -> bool {
+        self.task.is_finished()
+    }
+    // has a connection right now, not just a slot kept for it to come back
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed) && !self.has_left()
+    }
+    // back to the starting position for the next match
+    pub fn reset(&mut self) {
+        (self.x, self.y) = self.movement.start();
+        self.inputs.clear();
+        self.input_seq = 0;
+        self.input = Input::default();
+        self.moving = false;
+        self.ready = false;
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn is_ready(&self) -> bool {
+        self.ready
+    }
+    pub fn toggle_ready(&mut self) {
+        self.ready = !self.ready;
+    }
+    pub fn x(&self) -> u32 {
+        self.x
+    }
+    pub fn y(&self) -> u32 {
+        self.y
+    }
+    // inputs may arrive out of order once they travel over UDP, older ones are dropped
+    pub fn queue_input(&mut self, seq: u32, input: Input) {
+        if seq > self.input_seq && self.inputs.len() < MAX_QUEUED_INPUTS {
+            self.input_seq = seq;
+            self.inputs.push_back((seq, input));
+        }
+    }
+    pub fn input(&self) -> Input {
+        self.input
+    }
+    // applies the next queued input for one update_interval, the way the client predicts it.
+    // Returns its sequence number and whether the others should hear of the position:
+    // it moved, or it just stopped and their clients would otherwise keep it going.
+    // None if the client sent nothing
+    pub fn step(&mut self, rules: &GameRules) -> Option<(u32, bool)> {
+        let (seq, input) = self.inputs.pop_front()?;
+        self.input = input;
+        let x = (self.x as i64 + rules.step(input)).clamp(0, self.movement.max_x as i64) as u32;
+        let was_moving = self.moving;
+        self.moving = x != self.x;
+        self.x = x;
+        Some((seq, self.moving || was_moving))
     }
     pub fn move_to(&mut self, x: u32, y: u32) {
-        self.last_updated = time::Instant::now();
         self.x = x;
         self.y = y;
     }
-    pub fn fire(&mut self) -> Bullet {
+    pub fn fire(&mut self, speed: u32) -> Bullet {
         self.last_fired = time::Instant::now();
-        Bullet::new(self.x, self.y, self.max_y)
+        Bullet::new(self.x, self.y, self.movement.max_y, speed)
     }
     pub fn last_fired(&self) -> Duration {
         self.last_fired.elapsed()
     }
-    
-    pub fn last_updated(&self) -> Duration {
-        self.last_updated.elapsed()
+}
+
+impl Drop for Player {
+    // the connection closes with the player
+    fn drop(&mut self) {
+        self.task.abort();
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOVEMENT: Movement = Movement { max_x: 600, max_y: 600, max_speed: 1500, control: Control::Positions };
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn place_keeps_players_on_their_line() {
+        assert_eq!(MOVEMENT.place(250), (250, 500));
+        assert_eq!(MOVEMENT.place(9000), (600, 500));
+    }
+
+    #[test]
+    fn moves_at_full_speed_pass() {
+        let (mut budget, mut x) = (0.0, 0);
+        for _ in 0..5 {
+            assert!(MOVEMENT.allows(&mut budget, x, x + 150, ms(100)));
+            x += 150;
+        }
+    }
+
+    #[test]
+    fn bunched_up_positions_pass() {
+        let mut budget = 0.0;
+        assert!(MOVEMENT.allows(&mut budget, 0, 120, ms(190))); // held up by the network
+        assert!(MOVEMENT.allows(&mut budget, 120, 240, ms(10))); // right behind it
+    }
+
+    #[test]
+    fn jumps_are_turned_down() {
+        let mut budget = 0.0;
+        assert!(!MOVEMENT.allows(&mut budget, 0, 300, ms(0)));
+        // waiting does not save up for one
+        assert!(!MOVEMENT.allows(&mut budget, 0, 300, ms(5000)));
+        // neither does sending just often enough
+        let mut budget = 0.0;
+        let crossed = (0..3).all(|_| MOVEMENT.allows(&mut budget, 0, 300, ms(200)));
+        assert!(!crossed);
+    }
+}
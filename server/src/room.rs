@@ -0,0 +1,277 @@
+use std::{
+    sync::{atomic::{AtomicBool, Ordering}, Arc},
+    time::Duration,
+};
+
+use tokio::{
+    sync::mpsc::{error::SendError, Receiver, UnboundedSender},
+    time,
+};
+
+use common::*;
+
+use crate::{
+    clock::Clock,
+    config::Config,
+    player::{Heartbeat, Joining},
+    spectator::{Feed, Spectator},
+    team::{Roster, Team},
+};
+
+const RESULT_DELAY: Duration = Duration::from_secs(5); // clients show the result before the lobby returns
+
+#[derive(Debug)]
+pub enum GameResult {
+    Won,
+    Lost,
+    Forfeited, // no players left, see Config::empty_team
+    Aborted, // the other team stopped before the game was decided
+}
+
+// a single match, with its own lobby and pair of teams
+pub struct Room {
+    name: String,
+    max_players: u8,
+    countdown: u8, // in seconds
+    rematch: bool, // go back to the lobby with the same players after a match
+    heartbeat: Heartbeat,
+    clock: Clock,
+    top: Team,
+    bottom: Team,
+    spectators: Vec<Spectator>,
+
+    joins: Receiver<Joining>, // players the server placed here
+    overflow: UnboundedSender<Joining>, // players that asked for any room go back to the server when turned away
+    open: Arc<AtomicBool>, // whether the lobby still takes players
+}
+
+impl Room {
+    pub fn new(name: String, config: &Config, clock: Clock, joins: Receiver<Joining>, overflow: UnboundedSender<Joining>, open: Arc<AtomicBool>) -> Room {
+        let (mut top, mut bottom) = (Team::new(config, clock), Team::new(config, clock));
+        Self::swap_enemy_channels(&mut top, &mut bottom);
+
+        Room {
+            name,
+            max_players: config.players,
+            countdown: config.countdown,
+            rematch: config.rematch,
+            heartbeat: config.heartbeat(),
+            clock,
+            top,
+            bottom,
+            spectators: Vec::new(),
+            joins,
+            overflow,
+            open,
+        }
+    }
+
+    pub async fn run(mut self) {
+        while self.lobby().await {
+            self.play().await;
+            if !self.rematch {
+                break;
+            }
+            self.top.reset().await;
+            self.bottom.reset().await;
+        }
+
+        // players the server placed here in the meantime go back to be placed again
+        self.open.store(false, Ordering::Relaxed);
+        self.joins.close();
+        while let Some(joining) = self.joins.recv().await {
+            let _ = self.overflow.send(joining);
+        }
+        println!("closing room {}", self.name);
+        // dropping the teams stops the tasks of every player still here
+    }
+
+    async fn play(&mut self) {
+        self.open.store(false, Ordering::Relaxed);
+        self.top.start_game().await;
+        self.bottom.start_game().await;
+
+        let rosters = [self.top.roster(), self.bottom.roster()];
+        let (top, bottom, joins, overflow, name) = (&mut self.top, &mut self.bottom, &mut self.joins, &self.overflow, &self.name);
+        let (spectators, heartbeat, clock) = (&mut self.spectators, self.heartbeat, self.clock);
+        let game = async {
+            let results = tokio::join!(top.handle_team(), bottom.handle_team());
+            time::sleep(RESULT_DELAY).await;
+            results
+        };
+        tokio::pin!(game);
+        let (top_result, bottom_result) = loop {
+            tokio::select! {
+                results = &mut game => break results,
+                Some(joining) = joins.recv() => {
+                    if !joining.resume.is_empty() {
+                        Self::resume(&rosters, joining, name, true).await;
+                    } else if joining.spectate {
+                        let feeds = [rosters[0].watch(true).await, rosters[1].watch(true).await];
+                        Self::add_spectator(spectators, joining, name, heartbeat, clock, feeds).await;
+                    } else {
+                        Self::turn_away(overflow, joining, "the match already started").await;
+                    }
+                }
+            }
+        };
+
+        println!("room {}: top: {:?}, bottom: {:?}", self.name, top_result, bottom_result);
+    }
+
+    // takes players until the match is full and everyone is ready, then counts down to the start.
+    // Returns false once the last player left.
+    async fn lobby(&mut self) -> bool {
+        let mut top_rx = self.top.subscribe().await;
+        let mut bottom_rx = self.bottom.subscribe().await;
+        let mut countdown: Option<u8> = None;
+        let mut countdown_ticker = time::interval(Duration::from_secs(1));
+
+        // players staying for a rematch see the lobby again
+        let players = self.top.player_count().await + self.bottom.player_count().await;
+        if players > 0 {
+            self.broadcast_lobby().await;
+        }
+
+        loop {
+            let top_count = self.top.player_count().await;
+            let full = top_count + self.bottom.player_count().await >= self.max_players as usize;
+            self.open.store(!full, Ordering::Relaxed);
+
+            let changed = tokio::select! {
+                Some(joining) = self.joins.recv() => {
+                    if !joining.resume.is_empty() {
+                        let rosters = [self.top.roster(), self.bottom.roster()];
+                        Self::resume(&rosters, joining, &self.name, false).await
+                    } else if joining.spectate {
+                        let (rosters, lobby) = ([self.top.roster(), self.bottom.roster()], self.lobby_info().await);
+                        let mut feeds = [rosters[0].watch(false).await, rosters[1].watch(false).await];
+                        feeds[0].1.push(lobby);
+                        Self::add_spectator(&mut self.spectators, joining, &self.name, self.heartbeat, self.clock, feeds).await;
+                        false
+                    } else if full {
+                        Self::turn_away(&self.overflow, joining, "the room is full").await;
+                        false
+                    } else {
+                        let pid = joining.pid;
+                        // fill the teams evenly
+                        let team = if top_count < self.max_players as usize / 2 { &mut self.top } else { &mut self.bottom };
+                        match team.add_player(joining, &self.name).await {
+                            Ok(()) => true,
+                            Err(e) => { println!("player {} could not join room {}: {}", pid, self.name, e); false }
+                        }
+                    }
+                }
+                Ok(packet) = top_rx.recv() => Self::lobby_event(&mut self.top, packet).await,
+                Ok(packet) = bottom_rx.recv() => Self::lobby_event(&mut self.bottom, packet).await,
+                _ = countdown_ticker.tick(), if countdown.is_some() => {
+                    let seconds = countdown.unwrap() - 1;
+                    if seconds == 0 {
+                        return true;
+                    }
+                    countdown = Some(seconds);
+                    self.broadcast(Packet::Countdown{ seconds }).await;
+                    false
+                }
+            };
+            if !changed {
+                continue;
+            }
+
+            let players = self.top.player_count().await + self.bottom.player_count().await;
+            if players == 0 {
+                return false;
+            }
+            self.broadcast_lobby().await;
+
+            if players < self.max_players as usize || !self.top.all_ready().await || !self.bottom.all_ready().await {
+                countdown = None; // clients drop their countdown on LobbyInfo
+            } else if countdown.is_none() {
+                if self.countdown == 0 {
+                    return true;
+                }
+                println!("room {}: everyone is ready, starting in {}s", self.name, self.countdown);
+                countdown = Some(self.countdown);
+                self.broadcast(Packet::Countdown{ seconds: self.countdown }).await;
+                countdown_ticker.reset();
+            }
+        }
+    }
+
+    // returns whether the lobby changed
+    async fn lobby_event(team: &mut Team, packet: Packet) -> bool {
+        match packet {
+            Packet::PlayerEvent{ pid, event: PlayerEvent::Ready } => team.toggle_ready(pid).await,
+            Packet::PlayerDestroy(pid) => team.remove_player(pid).await,
+            _ => return false,
+        }
+        true
+    }
+
+    // returns whether the player came back
+    async fn resume(rosters: &[Roster], mut joining: Joining, room: &str, in_game: bool) -> bool {
+        for roster in rosters {
+            joining = match roster.resume(joining, room, in_game).await {
+                Ok(()) => return true,
+                Err(joining) => joining,
+            };
+        }
+        if let Err(e) = joining.reject("your session expired").await {
+            println!("could not reject player {}: {}", joining.pid, e);
+        }
+        false
+    }
+
+    async fn add_spectator(spectators: &mut Vec<Spectator>, joining: Joining, room: &str, heartbeat: Heartbeat, clock: Clock, feeds: [Feed; 2]) {
+        let pid = joining.pid;
+        let [top, bottom] = feeds;
+        match Spectator::new(joining, room, heartbeat, clock, top, bottom).await {
+            Ok(spectator) => {
+                spectators.retain(|spectator| !spectator.has_left());
+                spectators.push(spectator);
+            }
+            Err(e) => println!("spectator {} could not join room {}: {}", pid, room, e),
+        }
+    }
+
+    // players that asked for a specific room are rejected, the others are placed elsewhere by the server
+    async fn turn_away(overflow: &UnboundedSender<Joining>, joining: Joining, reason: &str) {
+        let mut joining = if joining.room.is_empty() {
+            match overflow.send(joining) {
+                Ok(()) => return,
+                Err(SendError(joining)) => joining,
+            }
+        } else {
+            joining
+        };
+        if let Err(e) = joining.reject(reason).await {
+            println!("could not reject player {}: {}", joining.pid, e);
+        }
+    }
+
+    async fn lobby_info(&self) -> Packet {
+        Packet::LobbyInfo{
+            top: self.top.get_lobby_players().await,
+            bottom: self.bottom.get_lobby_players().await,
+        }
+    }
+
+    async fn broadcast_lobby(&mut self) {
+        let lobby = self.lobby_info().await;
+        self.broadcast(lobby).await;
+    }
+
+    async fn broadcast(&mut self, packet: Packet) {
+        self.top.broadcast(packet.clone()).await;
+        self.bottom.broadcast(packet).await;
+    }
+
+    fn swap_enemy_channels(t1: &mut Team, t2: &mut Team) {
+        let t1_erx = t1.get_enemy_rx();
+        let t1_trx = t1.get_tcomms_rx();
+        t1.set_enemy_rx(t2.get_enemy_rx());
+        t1.set_tcomms_rx(t2.get_tcomms_rx());
+        t2.set_enemy_rx(t1_erx);
+        t2.set_tcomms_rx(t1_trx);
+    }
+}
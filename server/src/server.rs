@@ -1,13 +1,19 @@
-use tokio::net::TcpListener;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{atomic::{AtomicBool, Ordering}, Arc},
+    time::Duration,
+};
+
+use tokio::{
+    sync::mpsc::{self, error::SendError, Sender, UnboundedReceiver, UnboundedSender},
+    time,
+};
 use common::*;
 
-use crate::team::Team;
+use crate::{clock::Clock, config::Config, player::Joining, room::Room, transport::Listener};
 
-#[derive(Debug)]
-pub enum GameResult {
-    Won,
-    Lost,
-}
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100); // wait after a failed accept before trying again
 
 // enum Event {
 //     UpdatePlayer(String, u32, u32),
@@ -18,50 +24,150 @@ pub enum GameResult {
 //     AddEnemy(Bullet),
 // }
 
+struct RoomHandle {
+    joins: Sender<Joining>,
+    open: Arc<AtomicBool>,
+}
+
 pub struct Server {
-    listener: TcpListener,
+    listener: Listener,
+
+    config: Config,
+    clock: Clock,
+    next_pid: PlayerId,
+    next_room: u32,
+    rooms: HashMap<String, RoomHandle>,
+    sessions: HashMap<String, String>, // room of each player token
 
-    max_players: u8,
-    top: Team,
-    bottom: Team,
+    // handshaken players, also the ones a room turned away. Unbounded so a room never waits on the
+    // server, which may be waiting in place() for that room to take a player
+    joins_send: UnboundedSender<Joining>,
+    joins_recv: UnboundedReceiver<Joining>,
 }
 
 
 impl Server {
-    pub async fn new(width: u32, height: u32, max_players: u8, port: u16) -> Result<Server> {
-        let (mut top, mut bottom) = (Team::new(width, height), Team::new(width, height));
-        Self::swap_enemy_channels(&mut top, &mut bottom);
-        
+    pub async fn new(config: &Config) -> Result<Server> {
+        let codec = PacketCodec::new(config.wire_format);
+        let listener = Listener::bind(SocketAddr::new(config.bind, config.port), config.transport, codec).await?;
+        println!("listening on {} over {}", listener.local_addr()?, config.transport);
+        let (joins_send, joins_recv) = mpsc::unbounded_channel();
+
         Ok(Server {
-            listener: TcpListener::bind(format!("0.0.0.0:{}", port)).await?,
-            max_players,
-            top,
-            bottom,
+            listener,
+            config: config.clone(),
+            clock: Clock::new(),
+            next_pid: 0,
+            next_room: 0,
+            rooms: HashMap::new(),
+            sessions: HashMap::new(),
+            joins_send,
+            joins_recv,
         })
     }
 
-    pub async fn start(&mut self) -> Result<()> {
-        for _ in (0..self.max_players).step_by(2) {
-           self.top.add_player(&self.listener).await?;
-           self.bottom.add_player(&self.listener).await?;
+    pub async fn start(&mut self) {
+        loop {
+            tokio::select! {
+                accepted = self.listener.accept() => {
+                    let (stream_r, stream_w, addr) = match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            // out of file descriptors and the like, it may pass once others leave
+                            println!("failed to accept a connection: {}", e);
+                            time::sleep(ACCEPT_BACKOFF).await;
+                            continue;
+                        }
+                    };
+                    let pid = self.new_pid();
+                    let heartbeat = self.config.heartbeat();
+                    let joins = self.joins_send.clone();
+                    // handshake in its own task so a slow client does not hold up the others
+                    tokio::spawn(async move {
+                        match Joining::handshake(stream_r, stream_w, pid, heartbeat).await {
+                            Ok(joining) => { let _ = joins.send(joining); }
+                            Err(ProtocolError::UnexpectedEof) => println!("client {} left before joining", addr),
+                            Err(e) => println!("player join from {} failed: {}", addr, e),
+                        }
+                    });
+                }
+                Some(joining) = self.joins_recv.recv() => self.place(joining).await,
+            }
         }
+    }
 
-        self.top.start_game().await;
-        self.bottom.start_game().await;
+    // puts a player into the room it asked for, or into any open room, opening rooms as needed.
+    // Reconnecting players go back to the room they were in.
+    async fn place(&mut self, mut joining: Joining) {
+        loop {
+            self.rooms.retain(|_, room| !room.joins.is_closed()); // finished matches
+            let rooms = &self.rooms;
+            self.sessions.retain(|_, room| rooms.contains_key(room));
 
-        let (top_result, bottom_result) = tokio::join!(self.top.handle_team(), self.bottom.handle_team());
-        
-        println!("top: {:?}, bottom: {:?}", top_result, bottom_result);
-        tokio::time::sleep(std::time::Duration::from_secs(1)).await; // let other tasks finish
-        Ok(())
+            let name = if !joining.resume.is_empty() {
+                match self.sessions.get(&joining.resume) {
+                    Some(room) => room.clone(),
+                    None => return Self::reject(joining, "your session expired").await,
+                }
+            } else if joining.spectate { // only watches rooms that exist
+                let room = if joining.room.is_empty() {
+                    self.rooms.keys().next()
+                } else {
+                    self.rooms.get_key_value(&joining.room).map(|(name, _)| name)
+                };
+                match room {
+                    Some(room) => room.clone(),
+                    None => return Self::reject(joining, "there is no such match to watch").await,
+                }
+            } else if joining.room.is_empty() {
+                match self.rooms.iter().find(|(_, room)| room.open.load(Ordering::Relaxed)) {
+                    Some((name, _)) => name.clone(),
+                    None => self.new_room_name(),
+                }
+            } else {
+                joining.room.clone()
+            };
+            let (config, clock, overflow) = (&self.config, self.clock, &self.joins_send);
+            let room = self.rooms.entry(name.clone())
+                .or_insert_with(|| Self::open_room(name.clone(), config, clock, overflow.clone()));
+
+            if joining.resume.is_empty() && !joining.spectate {
+                self.sessions.insert(joining.token.clone(), name);
+            }
+            match room.joins.send(joining).await {
+                Ok(()) => return,
+                Err(SendError(returned)) => joining = returned, // the room closed in the meantime
+            }
+        }
+    }
+
+    async fn reject(mut joining: Joining, reason: &str) {
+        if let Err(e) = joining.reject(reason).await {
+            println!("could not reject player {}: {}", joining.pid, e);
+        }
+    }
+
+    fn open_room(name: String, config: &Config, clock: Clock, overflow: UnboundedSender<Joining>) -> RoomHandle {
+        let (joins_send, joins_recv) = mpsc::channel(16);
+        let open = Arc::new(AtomicBool::new(true));
+        println!("opening room {}", name);
+        tokio::spawn(Room::new(name, config, clock, joins_recv, overflow, open.clone()).run());
+        RoomHandle { joins: joins_send, open }
+    }
+
+    fn new_room_name(&mut self) -> String {
+        loop {
+            self.next_room += 1;
+            let name = format!("room-{}", self.next_room);
+            if !self.rooms.contains_key(&name) {
+                return name;
+            }
+        }
     }
 
-    fn swap_enemy_channels(t1: &mut Team, t2: &mut Team) {
-        let t1_erx = t1.get_enemy_rx();
-        let t1_trx = t1.get_tcomms_rx();
-        t1.set_enemy_rx(t2.get_enemy_rx());
-        t1.set_tcomms_rx(t2.get_tcomms_rx());
-        t2.set_enemy_rx(t1_erx);
-        t2.set_tcomms_rx(t1_trx);
+    // every connection takes one, the ids are wide enough not to wrap while a player still holds its own
+    fn new_pid(&mut self) -> PlayerId {
+        self.next_pid = self.next_pid.wrapping_add(1);
+        self.next_pid
     }
 }
@@ -0,0 +1,101 @@
+use std::time;
+
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    sync::broadcast,
+    task::JoinHandle,
+    time as ticker,
+};
+use common::*;
+
+use crate::{clock::Clock, player::{Heartbeat, Joining}, transport::PacketSink};
+
+// the packets of a team from now on, and the ones a spectator needs to catch up first
+pub type Feed = (broadcast::Receiver<Packet>, Vec<Packet>);
+
+// a client that follows both teams of a room without playing
+pub struct Spectator {
+    task: JoinHandle<()>, // forwards the packets of both teams to the socket
+}
+
+impl Spectator {
+    pub async fn new(joining: Joining, room: &str, heartbeat: Heartbeat, clock: Clock, top: Feed, bottom: Feed) -> Result<Spectator> {
+        let (pid, name) = (joining.pid, joining.name.clone());
+        let (mut stream_r, mut stream_w) = joining.into_streams();
+        stream_w.send(Packet::JoinAccepted { pid, room: room.to_string(), token: String::new() }).await?;
+        let ((mut top, top_catch_up), (mut bottom, bottom_catch_up)) = (top, bottom);
+        for packet in top_catch_up {
+            Self::forward(&mut stream_w, true, packet).await?;
+        }
+        for packet in bottom_catch_up {
+            Self::forward(&mut stream_w, false, packet).await?;
+        }
+        println!("{} is watching room {} as {}", name, room, pid);
+
+        let task = tokio::spawn(async move {
+            let mut heartbeat_ticker = ticker::interval(heartbeat.interval);
+            let mut last_heard = time::Instant::now();
+            loop {
+                let result = tokio::select! {
+                    Ok(packet) = top.recv() => Self::forward(&mut stream_w, true, packet).await.map_err(|e| e.to_string()),
+                    Ok(packet) = bottom.recv() => Self::forward(&mut stream_w, false, packet).await.map_err(|e| e.to_string()),
+                    _ = heartbeat_ticker.tick() => {
+                        if last_heard.elapsed() > heartbeat.timeout {
+                            Err(String::from("timed out"))
+                        } else {
+                            stream_w.send(Packet::Ping).await.map_err(|e| e.to_string())
+                        }
+                    }
+                    packet = stream_r.next() => {
+                        match packet {
+                            Some(Ok(packet)) => {
+                                last_heard = time::Instant::now();
+                                match packet {
+                                    Ok(Packet::TimeRequest { client_time }) => {
+                                        let reply = Packet::TimeReply { client_time, server_time: clock.now() };
+                                        stream_w.send(reply).await.map_err(|e| e.to_string())
+                                    }
+                                    Ok(packet @ (Packet::PlayerPos { .. } | Packet::PlayerEvent { .. })) => {
+                                        println!("ignored {:?} from spectator {}", packet, pid);
+                                        Ok(())
+                                    }
+                                    _ => Ok(()),
+                                }
+                            }
+                            Some(Err(e)) => Err(e.to_string()),
+                            None => Err(String::from("disconnected")),
+                        }
+                    }
+                };
+                if let Err(reason) = result {
+                    println!("spectator {} left: {}", pid, reason);
+                    return;
+                }
+            }
+        });
+        Ok(Spectator { task })
+    }
+
+    // tags the packets of a team with its side so the client knows which field they belong to.
+    // Spectators do not ack ticks, every Delta they get holds the whole field
+    async fn forward(stream_w: &mut PacketSink, top: bool, packet: Packet) -> Result<()> {
+        let packet = match packet {
+            Packet::PlayerEvent { .. } | Packet::PlayerInput { .. } => return Ok(()), // between players and their team
+            Packet::LobbyInfo { .. } | Packet::Countdown { .. } if !top => return Ok(()), // sent to both teams, forwarded once
+            Packet::LobbyInfo { .. } | Packet::Countdown { .. } => packet,
+            packet => Packet::Spectated { top, packet: Box::new(packet) },
+        };
+        stream_w.send(packet).await
+    }
+
+    pub fn has_left(&self) -> bool {
+        self.task.is_finished()
+    }
+}
+
+impl Drop for Spectator {
+    // the connection closes with the room
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
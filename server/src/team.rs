@@ -1,16 +1,16 @@
 use std::{
     collections::{HashMap, VecDeque}, 
     sync::Arc,
-    time::Duration
+    time::Duration,
 };
 
 use tokio::{
-    net::TcpListener, 
     sync::{
         broadcast, 
         mpsc::{self, Receiver, Sender},
         Mutex,
     }, 
+    task::JoinHandle,
     time,
 };
 
@@ -18,7 +18,7 @@ use nanoid::nanoid;
 
 use common::*;
 
-use crate::{bullet::Bullet, player::Player};
+use crate::{bullet::Bullet, clock::Clock, config::{Config, EmptyTeam}, player::{Heartbeat, Joining, Movement, Player}, room::GameResult};
 
 
 //#[derive(Clone)]
@@ -30,12 +30,17 @@ use crate::{bullet::Bullet, player::Player};
 pub struct Team {
     width: u32,
     height: u32,
+    rules: GameRules,
+    movement: Movement,
+    heartbeat: Heartbeat,
+    reconnect_grace: Duration,
+    empty_team: EmptyTeam,
 
     state: Arc<Mutex<State>>,
-    score: u32,
     //base: Vec<BaseState>,
     
     bullet_ticker: time::Interval,
+    ticker: time::Interval, // the team's tick: steps the players with Control::Inputs and sends the field
     
     p_recv: Option<broadcast::Receiver<Packet>>,
     tcomms_recv: Option<Receiver<Packet>>, // inter-team comms reciever; recieve bullet hits
@@ -45,42 +50,92 @@ pub struct Team {
 }
 
 struct State {
-    players: HashMap<String, Player>,
+    players: HashMap<PlayerId, Player>,
     bullets: VecDeque<(String, Bullet)>, 
     enemies: VecDeque<(String, Bullet)>,
     p_sender: Option<broadcast::Sender<Packet>>,
+    score: u32, // enemies of the other team shot down
+    health: u32, // enemies that may still reach the bottom before the game is lost
+    tick: u32, // update_intervals played, 0 before the first one
+    clock: Clock, // spawns and ticks are stamped with it
 }
 
 impl State {
-    pub fn new() -> (Self, broadcast::Receiver<Packet>) {
+    pub fn new(health: u32, clock: Clock) -> (Self, broadcast::Receiver<Packet>) {
         let (tx, rx) = broadcast::channel(1024);
         (State {
             players: HashMap::new(), 
             bullets: VecDeque::new(), 
             enemies: VecDeque::new(),
             p_sender: Some(tx),
+            score: 0,
+            health,
+            tick: 0,
+            clock,
         }, rx)
     }
+
+    // shoots if the player's gun is ready
+    fn fire(&mut self, pid: PlayerId, rules: &GameRules) {
+        let player = self.players.get_mut(&pid).unwrap();
+        if player.last_fired() > rules.fire_interval() {
+            let bullet = player.fire(rules.bullet_speed);
+            let id = nanoid!(BULLET_ID_LEN);
+            let time = self.clock.now();
+            self.p_sender.as_mut().unwrap().send(Packet::BulletCreate{ id: id.clone(), x: bullet.x(), y: bullet.y(), time }).unwrap();
+            self.bullets.push_back((id, bullet));
+        }
+    }
+
+    fn snapshot(&self) -> Packet {
+        let mut players: Vec<PlayerSnapshot> = self.players.iter()
+            .map(|(pid, player)| (*pid, player.x(), player.y()))
+            .collect();
+        players.sort_by_key(|(pid, _, _)| *pid);
+        let (bullets, enemies) = self.objects();
+        Packet::Snapshot{ players, bullets, enemies, score: self.score, health: self.health }
+    }
+
+    // the whole field at this tick, each player's task sends it against what its client has
+    fn delta(&self) -> Packet {
+        let (bullets, enemies) = self.objects();
+        Packet::Delta{ tick: self.tick, time: self.clock.now(), baseline: 0, bullets, enemies, removed: Vec::new() }
+    }
+
+    fn objects(&self) -> (Vec<BulletSnapshot>, Vec<BulletSnapshot>) {
+        (
+            self.bullets.iter().map(|(id, bullet)| (id.clone(), bullet.x(), bullet.y())).collect(),
+            self.enemies.iter().map(|(id, enemy)| (id.clone(), enemy.x(), enemy.y())).collect(),
+        )
+    }
 }
 
 
 impl Team {
-    pub fn new(width: u32, height: u32) -> Team {
+    pub fn new(config: &Config, clock: Clock) -> Team {
         let (enemy_tx, enemy_rx) = mpsc::channel(256);
         let (hit_tx, hit_rx) = mpsc::channel(32);
-        let (state, p_rx) = State::new();
-        
-        let mut bullet_ticker = time::interval(Duration::from_millis(BULLET_UPDATE_INTERVAL));
+        let rules = config.rules();
+        let (state, p_rx) = State::new(rules.win_score, clock);
+
+        let mut bullet_ticker = time::interval(rules.bullet_interval());
         bullet_ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        let mut ticker = time::interval(rules.update_interval());
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
 
         Team {
-            width,
-            height,
+            width: config.width,
+            height: config.height,
+            rules,
+            movement: config.movement(),
+            heartbeat: config.heartbeat(),
+            reconnect_grace: config.reconnect_grace(),
+            empty_team: config.empty_team,
             state: Arc::new(Mutex::new(state)),
-            score: 0,
             //base: vec![BaseState::Healthy; width as usize],
             
             bullet_ticker, // bullet speed
+            ticker,
             p_recv: Some(p_rx),
             enemy_recv: Some(enemy_rx),
             enemy_send: enemy_tx,
@@ -90,10 +145,17 @@ impl Team {
         }
     }
 
-    pub async fn handle_team(&mut self) -> Result<crate::server::GameResult> {
-        // State updates
+    pub async fn handle_team(&mut self) -> GameResult {
+        let state_updates = self.spawn_state_updates();
+        let result = self.run_game().await;
+        state_updates.abort();
+        result
+    }
+
+    fn spawn_state_updates(&mut self) -> JoinHandle<()> {
         let mut p_recv = self.p_recv.take().unwrap();
         let state = self.state.clone();
+        let rules = self.rules.clone();
         tokio::spawn(async move {
             loop {
                 if let Ok(packet) = p_recv.recv().await {
@@ -102,31 +164,29 @@ impl Team {
                         Packet::PlayerDestroy(pid) => {
                             state.players.remove(&pid);
                         }
-                        Packet::PlayerEvent{ pid, event } => {
-                            if let PlayerEvent::Fire = event {
-                                let player = state.players.get_mut(&pid);
-                                if player.is_none() {
-                                    println!("Invalid player id received: {}", pid);
-                                    continue;
-                                }
-                                let player = player.unwrap();
-                                if player.last_fired() > Duration::from_millis(PLAYER_FIRE_INTERVAL) {
-                                    let bullet = player.fire();
-                                    let id = nanoid!(BULLET_ID_LEN);
-                                    state.p_sender.as_mut().unwrap().send(Packet::BulletCreate{ id: id.clone(), x: bullet.x(), y: bullet.y() }).unwrap();
-                                    state.bullets.push_back((id, bullet));
-                                }
+                        Packet::PlayerEvent{ pid, event: PlayerEvent::Fire } => {
+                            if !state.players.contains_key(&pid) {
+                                println!("Invalid player id received: {}", pid);
+                                continue;
                             }
+                            state.fire(pid, &rules);
                         }
-                        Packet::PlayerPos{ pid, x, y } => {
+                        Packet::PlayerInput{ pid, seq, input } => {
+                            if let Some(player) = state.players.get_mut(&pid) {
+                                player.queue_input(seq, input);
+                            }
+                        }
+                        Packet::PlayerPos{ pid, x, y, .. } => {
                             let player = state.players.get_mut(&pid);
                             if player.is_none() {
                                 println!("Invalid player id received: '{}'", pid);
                                 continue;
                             }
-                            let player = player.unwrap();
-                            if player.last_updated() > Duration::from_millis(PLAYER_UPDATE_INTERVAL) {
-                                player.move_to(x, y);
+                            player.unwrap().move_to(x, y); // checked by the player's task
+                        }
+                        Packet::PlayerEvent{ pid, event: PlayerEvent::Resync } => {
+                            if let Some(player) = state.players.get(&pid) {
+                                player.send(state.snapshot());
                             }
                         }
                         Packet::GameWon | Packet::GameLost => {
@@ -137,23 +197,78 @@ impl Team {
                     }
                 }
             }
-        });
-        // bullet & enemy updates & collisions
+        })
+    }
+
+    // bullet & enemy updates & collisions
+    async fn run_game(&mut self) -> GameResult {
         let enemy_recv = self.enemy_recv.as_mut().unwrap();
         let tcomms_recv = self.tcomms_recv.as_mut().unwrap();
+        let mut empty = false; // no players left, or with EmptyTeam::Pause none connected
+        let mut paused_by_enemy = false;
+        let mut enemy_gone = false; // with EmptyTeam::Play, the other team has no players left either
         loop {
             tokio::select! {
                 _ = self.bullet_ticker.tick() => {
                     let state = &mut *self.state.lock().await;
                     let sender = state.p_sender.as_mut().unwrap().clone();
 
+                    // a paused team waits for its players to reconnect, once none of them can it gives up
+                    let gone = state.players.is_empty();
+                    if gone && self.empty_team == EmptyTeam::Pause {
+                        println!("everyone left, forfeited");
+                        let _ = self.tcomms_send.send(Packet::GameLost).await;
+                        return GameResult::Forfeited
+                    }
+                    let now_empty = match self.empty_team {
+                        EmptyTeam::Pause => !state.players.values().any(Player::is_connected),
+                        _ => gone,
+                    };
+                    if now_empty != empty {
+                        empty = now_empty;
+                        match self.empty_team {
+                            EmptyTeam::Forfeit => {
+                                println!("forfeited");
+                                let _ = self.tcomms_send.send(Packet::GameLost).await;
+                                return GameResult::Forfeited
+                            }
+                            EmptyTeam::Pause => {
+                                println!("{}", if empty { "paused" } else { "resumed" });
+                                let packet = if empty { Packet::GamePaused } else { Packet::GameResumed };
+                                if !paused_by_enemy {
+                                    sender.send(packet.clone()).unwrap();
+                                }
+                                if self.tcomms_send.send(packet).await.is_err() {
+                                    return GameResult::Aborted
+                                }
+                            }
+                            // GamePaused tells the other team we are gone, once both are nothing can end the match
+                            EmptyTeam::Play => {
+                                println!("everyone left, playing on");
+                                if self.tcomms_send.send(Packet::GamePaused).await.is_err() || enemy_gone {
+                                    println!("both teams left, aborted");
+                                    return GameResult::Aborted
+                                }
+                            }
+                        }
+                    }
+                    if empty && enemy_gone {
+                        println!("both teams left, aborted");
+                        return GameResult::Aborted
+                    }
+                    if paused_by_enemy || (empty && self.empty_team == EmptyTeam::Pause) {
+                        continue;
+                    }
+
                     let mut bullets_invalid = 0;
                     let mut collisions: Vec<(usize, usize)> = Vec::new();
                     // update bullets
                     for (i, (id, bullet)) in state.bullets.iter_mut().enumerate() {
                         if !bullet.fly() { // bullet reached top
                             println!("bullet {} transfered", id);
-                            self.enemy_send.send((id.clone(), bullet.clone())).await?;
+                            if self.enemy_send.send((id.clone(), bullet.clone())).await.is_err() {
+                                return GameResult::Aborted // other team is gone
+                            }
                             sender.send(Packet::BulletDestroy(id.to_string())).unwrap();
                             bullets_invalid += 1;
                             continue;
@@ -185,7 +300,10 @@ impl Team {
                             enemies_invalid += 1;
                             sender.send(Packet::EnemyDestroy(id.to_string())).unwrap();
                             sender.send(Packet::EnemyHit).unwrap();
-                            self.tcomms_send.send(Packet::EnemyHit).await?;
+                            state.health = state.health.saturating_sub(1);
+                            if self.tcomms_send.send(Packet::EnemyHit).await.is_err() {
+                                return GameResult::Aborted
+                            }
                         }
                     }
                     // remove enemies out of bounds
@@ -193,31 +311,77 @@ impl Team {
                         state.enemies.pop_front();
                     }
                 }
+                _ = self.ticker.tick(), if !(paused_by_enemy || (empty && self.empty_team == EmptyTeam::Pause)) => {
+                    let state = &mut *self.state.lock().await;
+                    state.tick += 1;
+                    if self.rules.control == Control::Inputs {
+                        let pids: Vec<PlayerId> = state.players.keys().copied().collect();
+                        for pid in pids {
+                            let player = state.players.get_mut(&pid).unwrap();
+                            let (seq, moving) = match player.step(&self.rules) {
+                                Some(step) => step,
+                                None => continue,
+                            };
+                            let (x, y) = (player.x(), player.y());
+                            player.send(Packet::InputAck{ seq, x, y });
+                            if moving {
+                                let time = state.clock.now();
+                                state.p_sender.as_mut().unwrap().send(Packet::PlayerPos{ pid, x, y, time }).unwrap();
+                            }
+                            if state.players[&pid].input().fire {
+                                state.fire(pid, &self.rules);
+                            }
+                        }
+                    }
+                    let delta = state.delta();
+                    state.p_sender.as_mut().unwrap().send(delta).unwrap();
+                }
                 Some((id, enemy)) = enemy_recv.recv() => {
                     let mut state = self.state.lock().await;
-                    state.p_sender.as_mut().unwrap().send(Packet::EnemyCreate{ id: id.clone(), x: enemy.x(), y: enemy.y() }).unwrap();
+                    let time = state.clock.now();
+                    state.p_sender.as_mut().unwrap().send(Packet::EnemyCreate{ id: id.clone(), x: enemy.x(), y: enemy.y(), time }).unwrap();
                     state.enemies.push_back((id, enemy));
                 }
                 Some(packet) = tcomms_recv.recv() => { // recieve from other team
-                    let mut state = self.state.lock().await;
+                    let state = &mut *self.state.lock().await;
                     let sender = state.p_sender.as_mut().unwrap();
 
                     match packet {
                         Packet::EnemyHit => {
                             sender.send(Packet::BulletHit).unwrap();
 
-                            self.score += 1;
-                            if self.score >= GAME_END_SCORE {
-                                self.tcomms_send.send(Packet::GameWon).await?;
+                            state.score += 1;
+                            if state.score >= self.rules.win_score {
+                                if self.tcomms_send.send(Packet::GameWon).await.is_err() {
+                                    return GameResult::Aborted
+                                }
                                 sender.send(Packet::GameWon).unwrap();
                                 println!("won");
-                                return Ok(crate::server::GameResult::Won) // won
+                                return GameResult::Won
                             }
                         }
                         Packet::GameWon => { // enemy other sent won
                             sender.send(Packet::GameLost).unwrap();
                             println!("lost");
-                            return Ok(crate::server::GameResult::Lost) // lost
+                            return GameResult::Lost
+                        }
+                        Packet::GameLost => { // the other team forfeited
+                            sender.send(Packet::GameWon).unwrap();
+                            println!("won");
+                            return GameResult::Won
+                        }
+                        Packet::GamePaused if self.empty_team == EmptyTeam::Play => enemy_gone = true,
+                        Packet::GamePaused => {
+                            paused_by_enemy = true;
+                            if !empty {
+                                sender.send(Packet::GamePaused).unwrap();
+                            }
+                        }
+                        Packet::GameResumed => {
+                            paused_by_enemy = false;
+                            if !empty {
+                                sender.send(Packet::GameResumed).unwrap();
+                            }
                         }
                         _ => (),
                     }   
@@ -228,30 +392,83 @@ impl Team {
 
     
 
-    pub async fn add_player(&mut self, listener: &TcpListener) -> Result<()> {
+    pub async fn add_player(&mut self, joining: Joining, room: &str) -> Result<()> {
         let mut state = self.state.lock().await;
-        let (id, player) = Player::new(
-            self.width,
-            self.height,
-            &listener,
+        let pid = joining.pid;
+        let player = Player::new(
+            self.movement,
+            joining,
+            room,
+            self.heartbeat,
+            self.reconnect_grace,
+            state.clock,
             state.p_sender.as_mut().unwrap().clone(),
-            state.p_sender.as_mut().unwrap().subscribe()
         ).await?;
-        state.players.insert(id, player);
+        state.players.insert(pid, player);
         Ok(())
     }
 
-    pub async fn get_pids(&self) -> Vec<String> {
+    // ready for another match with the same players
+    pub async fn reset(&mut self) {
+        let mut state = self.state.lock().await;
+        state.bullets.clear();
+        state.enemies.clear();
+        state.players.retain(|_, player| !player.has_left()); // left after the game was decided
+        state.players.values_mut().for_each(Player::reset);
+        self.p_recv = Some(state.p_sender.as_mut().unwrap().subscribe());
+        state.score = 0;
+        state.health = self.rules.win_score;
+        state.tick = 0;
+        // whatever the other team sent after the game was decided
+        while self.enemy_recv.as_mut().unwrap().try_recv().is_ok() {}
+        while self.tcomms_recv.as_mut().unwrap().try_recv().is_ok() {}
+    }
+
+    pub async fn remove_player(&mut self, pid: PlayerId) {
+        self.state.lock().await.players.remove(&pid);
+    }
+
+    pub async fn toggle_ready(&mut self, pid: PlayerId) {
+        if let Some(player) = self.state.lock().await.players.get_mut(&pid) {
+            player.toggle_ready();
+        }
+    }
+
+    pub async fn player_count(&self) -> usize {
+        self.state.lock().await.players.len()
+    }
+
+    pub async fn all_ready(&self) -> bool {
+        self.state.lock().await.players.values().all(|player| player.is_ready())
+    }
+
+    pub async fn get_lobby_players(&self) -> Vec<LobbyPlayer> {
         let state = self.state.lock().await;
-        state.players.keys().cloned().collect()
+        let mut players: Vec<LobbyPlayer> = state.players.iter()
+            .map(|(pid, player)| (*pid, player.name().to_string(), player.is_ready()))
+            .collect();
+        players.sort_by_key(|(pid, _, _)| *pid); // keep the join order
+        players
     }
 
-    pub async fn start_game(&mut self) {
-        self.broadcast(Packet::GameInfo{
+    pub async fn subscribe(&mut self) -> broadcast::Receiver<Packet> {
+        self.state.lock().await.p_sender.as_mut().unwrap().subscribe()
+    }
+
+    pub fn roster(&self) -> Roster {
+        Roster {
             width: self.width,
             height: self.height,
-            pids: self.get_pids().await,
-        }).await;
+            rules: self.rules.clone(),
+            state: self.state.clone(),
+        }
+    }
+
+    pub async fn start_game(&mut self) {
+        let state = self.state.lock().await;
+        let sender = state.p_sender.as_ref().unwrap();
+        sender.send(self.roster().game_info(&state)).unwrap();
+        sender.send(state.snapshot()).unwrap(); // where everyone starts
     }
 
     pub async fn broadcast(&mut self, packet: Packet) {
@@ -260,15 +477,74 @@ impl Team {
     }
 
     pub fn get_enemy_rx(&mut self) -> Receiver<(String, Bullet)> {
-        return self.enemy_recv.take().unwrap();
+        self.enemy_recv.take().unwrap()
     }
     pub fn set_enemy_rx(&mut self, rx: Receiver<(String, Bullet)>) {
         self.enemy_recv = Some(rx);
     }
     pub fn get_tcomms_rx(&mut self) -> Receiver<Packet> {
-        return self.tcomms_recv.take().unwrap();
+        self.tcomms_recv.take().unwrap()
     }
     pub fn set_tcomms_rx(&mut self, rx: Receiver<Packet>) {
         self.tcomms_recv = Some(rx);
     }
-}
\ No newline at end of file
+}
+
+// the part of a team that lets players back in, usable while the team plays
+pub struct Roster {
+    width: u32,
+    height: u32,
+    rules: GameRules,
+    state: Arc<Mutex<State>>,
+}
+
+impl Roster {
+    fn game_info(&self, state: &State) -> Packet {
+        Packet::GameInfo{
+            version: PROTOCOL_VERSION,
+            width: self.width,
+            height: self.height,
+            rules: self.rules.clone(),
+            players: state.players.iter().map(|(pid, player)| (*pid, player.name().to_string())).collect(),
+        }
+    }
+
+    // a subscription for a spectator, along with the game so far if it is running
+    pub async fn watch(&self, in_game: bool) -> (broadcast::Receiver<Packet>, Vec<Packet>) {
+        let state = self.state.lock().await;
+        let reciever = state.p_sender.as_ref().unwrap().subscribe();
+        let catch_up = if in_game { vec![self.game_info(&state), state.snapshot()] } else { Vec::new() };
+        (reciever, catch_up)
+    }
+
+    // gives a reconnecting client its player back along with the state it missed,
+    // or hands the client back if no player of this team has its token
+    pub async fn resume(&self, mut joining: Joining, room: &str, in_game: bool) -> std::result::Result<(), Joining> {
+        // the team plays on while the client is caught up, the packets it misses meanwhile wait in the subscription
+        let (pid, reciever, missed) = {
+            let state = self.state.lock().await;
+            let pid = match state.players.iter().find(|(_, player)| player.token() == joining.resume && !player.has_left()) {
+                Some((pid, _)) => *pid,
+                None => return Err(joining),
+            };
+            let reciever = state.p_sender.as_ref().unwrap().subscribe();
+            let mut missed = vec![Packet::JoinAccepted{ pid, room: room.to_string(), token: joining.resume.clone() }];
+            if in_game {
+                missed.push(self.game_info(&state));
+                missed.push(state.snapshot());
+            }
+            (pid, reciever, missed)
+        };
+        for packet in missed {
+            if let Err(e) = joining.send(packet).await {
+                println!("player {} could not resume: {}", pid, e);
+                return Ok(());
+            }
+        }
+        match self.state.lock().await.players.get(&pid) {
+            Some(player) => player.resume(joining, reciever),
+            None => println!("player {} left while resuming", pid),
+        }
+        Ok(())
+    }
+}
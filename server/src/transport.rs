@@ -0,0 +1,184 @@
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::BuildHasher,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures::{sink, stream, Sink, Stream};
+use tokio::{
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::mpsc::{self, error::TrySendError, Receiver, Sender},
+    time as ticker,
+};
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+use common::*;
+
+const RESEND_CHECK: Duration = Duration::from_millis(50); // how often a link looks for packets to send again
+const LINGER: Duration = Duration::from_secs(2); // how long a closed link keeps sending its last reliable packets
+
+// the two halves of a client connection, whatever it goes over
+pub type PacketStream = Pin<Box<dyn Stream<Item = Result<Result<Packet>>> + Send>>;
+pub type PacketSink = Pin<Box<dyn Sink<Packet, Error = ProtocolError> + Send>>;
+
+// where clients connect, see Config::transport
+pub enum Listener {
+    Tcp(TcpListener, PacketCodec),
+    Udp(UdpListener),
+}
+
+impl Listener {
+    pub async fn bind(addr: SocketAddr, transport: Transport, codec: PacketCodec) -> io::Result<Listener> {
+        match transport {
+            Transport::Tcp => Ok(Listener::Tcp(TcpListener::bind(addr).await?, codec)),
+            Transport::Udp => Ok(Listener::Udp(UdpListener::bind(addr, codec).await?)),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Listener::Tcp(listener, _) => listener.local_addr(),
+            Listener::Udp(listener) => listener.socket.local_addr(),
+        }
+    }
+
+    // waits for the next client
+    pub async fn accept(&mut self) -> io::Result<(PacketStream, PacketSink, SocketAddr)> {
+        match self {
+            Listener::Tcp(listener, codec) => {
+                let (stream, addr) = listener.accept().await?;
+                let (stream_r, stream_w) = Self::split(stream, *codec);
+                Ok((stream_r, stream_w, addr))
+            }
+            Listener::Udp(listener) => listener.accept().await,
+        }
+    }
+
+    fn split(stream: TcpStream, codec: PacketCodec) -> (PacketStream, PacketSink) {
+        let (stream_r, stream_w) = stream.into_split();
+        (Box::pin(FramedRead::new(stream_r, codec)), Box::pin(FramedWrite::new(stream_w, codec)))
+    }
+}
+
+// one socket for all clients. A task of its own reads it, so datagrams keep flowing while the server is busy;
+// each client gets a Link and a task that feeds it their datagrams
+pub struct UdpListener {
+    socket: Arc<UdpSocket>,
+    connections: Receiver<(PacketStream, PacketSink, SocketAddr)>,
+}
+
+impl UdpListener {
+    async fn bind(addr: SocketAddr, codec: PacketCodec) -> io::Result<UdpListener> {
+        let socket = Arc::new(UdpSocket::bind(addr).await?);
+        let (connections_send, connections) = mpsc::channel(16);
+        tokio::spawn(Self::demux(socket.clone(), codec, connections_send));
+        Ok(UdpListener { socket, connections })
+    }
+
+    async fn accept(&mut self) -> io::Result<(PacketStream, PacketSink, SocketAddr)> {
+        self.connections.recv().await.ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    // hands datagrams to the clients they are from, and new clients to accept
+    async fn demux(socket: Arc<UdpSocket>, codec: PacketCodec, connections: Sender<(PacketStream, PacketSink, SocketAddr)>) {
+        let mut peers: HashMap<SocketAddr, Sender<Vec<u8>>> = HashMap::new();
+        let secret = RandomState::new(); // keys the cookies, only this server can tell them apart from made up ones
+        let mut buf = vec![0; MAX_DATAGRAM_LEN];
+        while !connections.is_closed() {
+            let (len, addr) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    println!("failed to receive a datagram: {}", e);
+                    continue;
+                }
+            };
+            let datagram = buf[..len].to_vec();
+            let datagram = match peers.get(&addr) {
+                Some(peer) => match peer.try_send(datagram) {
+                    Err(TrySendError::Closed(datagram)) => datagram, // its connection is over, maybe this opens a new one
+                    _ => continue, // a full queue drops it, like a full socket buffer would
+                },
+                None => datagram,
+            };
+            // a client only gets a link once it shows it can read what is sent to its address
+            if Link::opens(&datagram) {
+                let _ = socket.send_to(&Link::cookie(secret.hash_one(addr)), addr).await;
+                continue;
+            }
+            if Link::echoed_cookie(&datagram) != Some(secret.hash_one(addr)) {
+                continue;
+            }
+
+            peers.retain(|_, peer| !peer.is_closed());
+            let (datagrams_send, datagrams_recv) = mpsc::channel(64);
+            let (packets_send, packets_recv) = mpsc::channel(64);
+            let _ = datagrams_send.try_send(datagram);
+
+            let link = Arc::new(Mutex::new(Link::new(codec)));
+            let stream_r = stream::unfold(packets_recv, |mut packets| async move {
+                packets.recv().await.map(|packet| (Ok(packet), packets))
+            });
+            let stream_w = sink::unfold((link.clone(), socket.clone(), addr), |(link, socket, addr), packet| async move {
+                let datagram = link.lock().unwrap().send(packet, Instant::now())?;
+                socket.send_to(&datagram, addr).await?;
+                Ok::<_, ProtocolError>((link, socket, addr))
+            });
+            // while the server is not accepting, the client's next try may get through
+            if connections.try_send((Box::pin(stream_r), Box::pin(stream_w), addr)).is_ok() {
+                peers.insert(addr, datagrams_send);
+                tokio::spawn(Self::run(link, socket.clone(), addr, datagrams_recv, packets_send));
+            }
+        }
+    }
+
+    // takes in the datagrams of a client, acks them and sends again what it did not ack.
+    // Runs until the connection is dropped and the client has everything, or gave up on getting it
+    async fn run(link: Arc<Mutex<Link>>, socket: Arc<UdpSocket>, addr: SocketAddr, mut datagrams: Receiver<Vec<u8>>, packets: Sender<Result<Packet>>) {
+        let mut resend_ticker = ticker::interval(RESEND_CHECK);
+        let mut closed_at = None;
+        loop {
+            tokio::select! {
+                datagram = datagrams.recv() => {
+                    let datagram = match datagram {
+                        Some(datagram) => datagram,
+                        None => break, // the listener is gone
+                    };
+                    let (ack, received) = {
+                        let mut link = link.lock().unwrap();
+                        let ack = link.receive(&datagram).unwrap_or_else(|e| {
+                            println!("invalid datagram from {}: {}", addr, e);
+                            None
+                        });
+                        (ack, std::iter::from_fn(|| link.recv()).collect::<Vec<_>>())
+                    };
+                    if let Some(ack) = ack {
+                        let _ = socket.send_to(&ack, addr).await;
+                    }
+                    for packet in received {
+                        let _ = packets.send(packet).await; // nobody reads them anymore once the connection is dropped
+                    }
+                }
+                _ = resend_ticker.tick() => {
+                    let (resend, idle) = {
+                        let mut link = link.lock().unwrap();
+                        (link.resend(Instant::now()), link.is_idle())
+                    };
+                    for datagram in resend {
+                        let _ = socket.send_to(&datagram, addr).await;
+                    }
+                    // the sink holds the only other reference
+                    if Arc::strong_count(&link) == 1 {
+                        let closed_at = *closed_at.get_or_insert_with(Instant::now);
+                        if idle || closed_at.elapsed() > LINGER {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}